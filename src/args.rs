@@ -1,11 +1,20 @@
+use anyhow::anyhow;
 use simplelog::LevelFilter;
 use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
 use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 use structopt::StructOpt;
 
+use crate::cron::Recurrence;
 use crate::duration::parse_duration;
+use crate::env::Environment;
+use crate::format::Format;
+use crate::kill::{KillPolicy, Signal};
+use crate::retry::{Backoff, RetryOn, RetryPolicy};
 use crate::template::Template;
+use crate::time::parse_time;
 
 #[derive(StructOpt)]
 pub struct StartServerCommand {
@@ -15,6 +24,144 @@ pub struct StartServerCommand {
     pub log_level: LevelFilter,
     #[structopt(short = "l", long, help = "Log file")]
     pub log_file: Option<PathBuf>,
+    #[structopt(long, help = "State directory")]
+    pub state_dir: Option<PathBuf>,
+    #[structopt(
+        short = "c",
+        long,
+        help = "Create queues defined in configuration file"
+    )]
+    pub config: Option<PathBuf>,
+}
+
+#[derive(StructOpt)]
+pub struct StopServerCommand {
+    #[structopt(long, help = "Wait for running tasks to finish before stopping")]
+    pub drain: bool,
+    #[structopt(
+        long,
+        help = "Also run queued tasks before stopping",
+        requires("drain")
+    )]
+    pub queued: bool,
+    #[structopt(
+        short = "T",
+        long,
+        help = "Stop tasks that have not finished after given time",
+        requires("drain"),
+        parse(try_from_str = parse_duration)
+    )]
+    pub timeout: Option<Duration>,
+}
+
+#[derive(StructOpt)]
+pub struct CheckConfigCommand {
+    #[structopt(help = "Configuration file")]
+    pub path: PathBuf,
+}
+
+#[derive(StructOpt)]
+pub struct RetryArgs {
+    #[structopt(long, help = "Maximum number of attempts")]
+    pub max_attempts: Option<u32>,
+    #[structopt(long, help = "Delay between attempts", parse(try_from_str = parse_duration))]
+    pub retry_delay: Option<Duration>,
+    #[structopt(long, help = "Retry delay backoff (fixed or exponential)")]
+    pub retry_backoff: Option<Backoff>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Retry on failure, timeout or specific exit code"
+    )]
+    pub retry_on: Vec<RetryOn>,
+}
+
+impl From<RetryArgs> for RetryPolicy {
+    fn from(args: RetryArgs) -> Self {
+        RetryPolicy {
+            max_attempts: args.max_attempts,
+            delay: args.retry_delay,
+            backoff: args.retry_backoff,
+            on: args.retry_on,
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct KillArgs {
+    #[structopt(long, help = "Signal sent to stop a task [default: TERM]")]
+    pub kill_signal: Option<Signal>,
+    #[structopt(
+        long,
+        help = "Time to wait for a task to stop before killing it [default: 10s]",
+        parse(try_from_str = parse_duration)
+    )]
+    pub kill_grace: Option<Duration>,
+}
+
+impl From<KillArgs> for KillPolicy {
+    fn from(args: KillArgs) -> Self {
+        KillPolicy {
+            signal: args.kill_signal,
+            grace: args.kill_grace,
+        }
+    }
+}
+
+#[derive(StructOpt)]
+pub struct EnvArgs {
+    #[structopt(
+        short = "e",
+        long,
+        number_of_values = 1,
+        help = "Set environment variable (KEY=VALUE, or KEY to pass its current value)"
+    )]
+    pub env: Vec<String>,
+    #[structopt(long, help = "Do not inherit the environment of the server")]
+    pub clear_env: bool,
+    #[structopt(
+        long,
+        conflicts_with_all = &["clear-env", "forward-env"],
+        help = "Inherit the environment of the server even if the queue clears it"
+    )]
+    pub inherit_env: bool,
+    #[structopt(
+        long,
+        help = "Pass the whole current environment instead of the server's (implies --clear-env)"
+    )]
+    pub forward_env: bool,
+}
+
+impl TryFrom<EnvArgs> for Environment {
+    type Error = anyhow::Error;
+
+    fn try_from(args: EnvArgs) -> Result<Self, Self::Error> {
+        let mut vars: BTreeMap<String, String> = if args.forward_env {
+            std::env::vars().collect()
+        } else {
+            BTreeMap::new()
+        };
+        for var in args.env {
+            match var.find('=') {
+                Some(index) => {
+                    vars.insert(var[..index].to_string(), var[index + 1..].to_string());
+                }
+                None => {
+                    let value = std::env::var(&var)
+                        .map_err(|_| anyhow!("environment variable '{}' is not set", var))?;
+                    vars.insert(var, value);
+                }
+            }
+        }
+        let clear = if args.clear_env || args.forward_env {
+            Some(true)
+        } else if args.inherit_env {
+            Some(false)
+        } else {
+            None
+        };
+        Ok(Environment { vars, clear })
+    }
 }
 
 #[derive(StructOpt)]
@@ -30,7 +177,7 @@ pub struct CreateQueueCommand {
     pub max_parallel: usize,
     #[structopt(short = "f", long, help = "Output to file")]
     pub file: Option<PathBuf>,
-    #[structopt(short = "s", long, help = "Output to stdin", conflicts_with("output"))]
+    #[structopt(short = "s", long, help = "Output to stdout", conflicts_with("file"))]
     pub stdout: bool,
     #[structopt(short = "d", long, help = "Default working directory")]
     pub dir: Option<PathBuf>,
@@ -38,6 +185,18 @@ pub struct CreateQueueCommand {
     pub timeout: Option<Duration>,
     #[structopt(short = "t", long, help = "Task template")]
     pub template: Option<Template>,
+    #[structopt(flatten)]
+    pub retry: RetryArgs,
+    #[structopt(flatten)]
+    pub kill: KillArgs,
+    #[structopt(flatten)]
+    pub env: EnvArgs,
+    #[structopt(
+        long,
+        default_value = "100",
+        help = "Number of finished tasks to remember"
+    )]
+    pub history_size: usize,
 }
 
 #[derive(StructOpt)]
@@ -46,23 +205,216 @@ pub struct RemoveQueueCommand {
     pub name: String,
 }
 
+#[derive(StructOpt)]
+pub struct UpdateQueueCommand {
+    #[structopt(short = "n", long, default_value = "default", help = "Queue name")]
+    pub name: String,
+    #[structopt(short = "p", long, help = "Maximum number of parallel tasks")]
+    pub max_parallel: Option<usize>,
+    #[structopt(short = "f", long, help = "Output to file")]
+    pub file: Option<PathBuf>,
+    #[structopt(short = "s", long, help = "Output to stdout", conflicts_with("file"))]
+    pub stdout: bool,
+    #[structopt(short = "d", long, help = "Default working directory")]
+    pub dir: Option<PathBuf>,
+    #[structopt(long, help = "Remove default working directory", conflicts_with("dir"))]
+    pub no_dir: bool,
+    #[structopt(short = "T", long, help = "Default task timeout", parse(try_from_str = parse_duration))]
+    pub timeout: Option<Duration>,
+    #[structopt(long, help = "Remove default task timeout", conflicts_with("timeout"))]
+    pub no_timeout: bool,
+    #[structopt(short = "t", long, help = "Task template")]
+    pub template: Option<Template>,
+    #[structopt(long, help = "Remove task template", conflicts_with("template"))]
+    pub no_template: bool,
+}
+
+#[derive(StructOpt)]
+pub struct ShowQueueCommand {
+    #[structopt(short = "n", long, default_value = "default", help = "Queue name")]
+    pub name: String,
+}
+
+#[derive(StructOpt)]
+pub struct PauseQueueCommand {
+    #[structopt(short = "n", long, default_value = "default", help = "Queue name")]
+    pub name: String,
+}
+
+#[derive(StructOpt)]
+pub struct ResumeQueueCommand {
+    #[structopt(short = "n", long, default_value = "default", help = "Queue name")]
+    pub name: String,
+}
+
 #[derive(StructOpt)]
 pub struct SendTaskCommand {
     #[structopt(short = "n", long, default_value = "default", help = "Task name")]
     pub name: String,
     #[structopt(short = "d", long, help = "Working directory")]
     pub dir: Option<PathBuf>,
+    #[structopt(long, help = "Do not fall back to the current directory")]
+    pub no_cwd: bool,
+    #[structopt(short = "T", long, help = "Task timeout", parse(try_from_str = parse_duration))]
+    pub timeout: Option<Duration>,
+    #[structopt(short = "w", long, help = "Wait for the task to finish")]
+    pub wait: bool,
+    #[structopt(
+        short = "p",
+        long,
+        default_value = "0",
+        allow_hyphen_values = true,
+        help = "Task priority"
+    )]
+    pub priority: i32,
+    #[structopt(
+        long,
+        help = "Start the task at given time ([YYYY-MM-DD] [HH:MM[:SS]])",
+        parse(try_from_str = parse_time)
+    )]
+    pub at: Option<SystemTime>,
+    #[structopt(
+        long,
+        help = "Start the task after given delay",
+        parse(try_from_str = parse_duration),
+        conflicts_with("at")
+    )]
+    pub delay: Option<Duration>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Start the task after given task has succeeded"
+    )]
+    pub after: Vec<u64>,
+    #[structopt(
+        long,
+        number_of_values = 1,
+        help = "Start the task after given task has finished in any state"
+    )]
+    pub after_any: Vec<u64>,
+    #[structopt(long, help = "Pass standard input to the task")]
+    pub stdin: bool,
+    #[structopt(
+        long,
+        help = "Read standard input of the task from file",
+        conflicts_with("stdin")
+    )]
+    pub stdin_file: Option<PathBuf>,
+    #[structopt(flatten)]
+    pub retry: RetryArgs,
+    #[structopt(flatten)]
+    pub kill: KillArgs,
+    #[structopt(flatten)]
+    pub env: EnvArgs,
+    pub args: Vec<String>,
+}
+
+#[derive(StructOpt)]
+pub struct AddScheduleCommand {
+    #[structopt(short = "n", long, default_value = "default", help = "Queue name")]
+    pub name: String,
+    #[structopt(short = "d", long, help = "Working directory")]
+    pub dir: Option<PathBuf>,
+    #[structopt(long, help = "Do not fall back to the current directory")]
+    pub no_cwd: bool,
     #[structopt(short = "T", long, help = "Task timeout", parse(try_from_str = parse_duration))]
     pub timeout: Option<Duration>,
+    #[structopt(
+        short = "p",
+        long,
+        default_value = "0",
+        allow_hyphen_values = true,
+        help = "Task priority"
+    )]
+    pub priority: i32,
+    #[structopt(
+        long,
+        help = "Skip starting a task while the previous one has not finished"
+    )]
+    pub skip_if_running: bool,
+    #[structopt(flatten)]
+    pub retry: RetryArgs,
+    #[structopt(flatten)]
+    pub kill: KillArgs,
+    #[structopt(flatten)]
+    pub env: EnvArgs,
+    #[structopt(help = "Cron expression or \"every <duration>\"")]
+    pub when: Recurrence,
     pub args: Vec<String>,
 }
 
+#[derive(StructOpt)]
+pub struct ListSchedulesCommand {
+    #[structopt(short = "n", long, default_value = "default", help = "Queue name")]
+    pub name: String,
+}
+
+#[derive(StructOpt)]
+pub struct RemoveScheduleCommand {
+    #[structopt(help = "Schedule ID")]
+    pub id: u64,
+}
+
+#[derive(StructOpt)]
+#[allow(clippy::large_enum_variant)]
+pub enum ScheduleCommand {
+    #[structopt(about = "Add recurring task to a queue", display_order = 0)]
+    Add(AddScheduleCommand),
+    #[structopt(about = "List recurring tasks of a queue", display_order = 1)]
+    List(ListSchedulesCommand),
+    #[structopt(about = "Remove recurring task", display_order = 2)]
+    Remove(RemoveScheduleCommand),
+}
+
 #[derive(StructOpt)]
 pub struct ListTasksCommand {
     #[structopt(short = "n", long, default_value = "default", help = "Task name")]
     pub name: String,
 }
 
+#[derive(StructOpt)]
+pub struct LogsCommand {
+    #[structopt(help = "Task ID")]
+    pub id: u64,
+    #[structopt(short = "o", long, help = "Print only stdout of the task")]
+    pub stdout: bool,
+    #[structopt(
+        short = "e",
+        long,
+        help = "Print only stderr of the task",
+        conflicts_with("stdout")
+    )]
+    pub stderr: bool,
+    #[structopt(short = "f", long, help = "Follow output until the task exits")]
+    pub follow: bool,
+}
+
+#[derive(StructOpt)]
+pub struct AttachCommand {
+    #[structopt(help = "Task ID")]
+    pub id: u64,
+}
+
+#[derive(StructOpt)]
+pub struct HistoryCommand {
+    #[structopt(short = "n", long, default_value = "default", help = "Queue name")]
+    pub name: String,
+}
+
+#[derive(StructOpt)]
+pub struct WaitCommand {
+    #[structopt(short = "n", long, default_value = "default", help = "Queue name")]
+    pub name: String,
+    #[structopt(help = "Task ID")]
+    pub id: Option<u64>,
+}
+
+#[derive(StructOpt)]
+pub struct CancelTaskCommand {
+    #[structopt(help = "Task ID")]
+    pub id: u64,
+}
+
 #[derive(StructOpt)]
 pub enum Command {
     #[structopt(
@@ -72,7 +424,7 @@ pub enum Command {
     )]
     StartServer(StartServerCommand),
     #[structopt(about = "Stop queue server", visible_alias = "stop", display_order = 1)]
-    StopServer,
+    StopServer(StopServerCommand),
     #[structopt(
         about = "Create new task queue",
         visible_alias = "create",
@@ -85,6 +437,12 @@ pub enum Command {
         display_order = 3
     )]
     RemoveQueue(RemoveQueueCommand),
+    #[structopt(
+        about = "Change settings of a task queue",
+        visible_alias = "update",
+        display_order = 12
+    )]
+    UpdateQueue(UpdateQueueCommand),
     #[structopt(about = "List queues", visible_alias = "queues", display_order = 4)]
     ListQueues,
     #[structopt(
@@ -99,12 +457,60 @@ pub enum Command {
         display_order = 6
     )]
     ListTasks(ListTasksCommand),
+    #[structopt(about = "Cancel task", visible_alias = "cancel", display_order = 7)]
+    CancelTask(CancelTaskCommand),
+    #[structopt(
+        about = "List finished tasks in a queue",
+        visible_alias = "history",
+        display_order = 8
+    )]
+    ListHistory(HistoryCommand),
+    #[structopt(
+        about = "Wait for a task or all tasks in a queue to finish",
+        display_order = 9
+    )]
+    Wait(WaitCommand),
+    #[structopt(
+        about = "Stop starting new tasks from a queue",
+        visible_alias = "pause",
+        display_order = 10
+    )]
+    PauseQueue(PauseQueueCommand),
+    #[structopt(
+        about = "Resume starting tasks from a paused queue",
+        visible_alias = "resume",
+        display_order = 11
+    )]
+    ResumeQueue(ResumeQueueCommand),
+    #[structopt(about = "Print output of a task", display_order = 13)]
+    Logs(LogsCommand),
+    #[structopt(about = "Follow output of a task until it exits", display_order = 14)]
+    Attach(AttachCommand),
+    #[structopt(about = "Manage recurring tasks", display_order = 15)]
+    Schedule(ScheduleCommand),
+    #[structopt(
+        about = "Show settings and statistics of a queue",
+        visible_alias = "show",
+        display_order = 16
+    )]
+    ShowQueue(ShowQueueCommand),
+    #[structopt(about = "Validate configuration file", display_order = 17)]
+    CheckConfig(CheckConfigCommand),
+    #[structopt(about = "Reload configuration file of the server", display_order = 18)]
+    Reload,
 }
 
 #[derive(StructOpt)]
 pub struct GlobalArgs {
     #[structopt(short = "s", long, help = "Server socket path")]
     pub socket: Option<PathBuf>,
+    #[structopt(
+        long,
+        default_value = "plain",
+        possible_values = &["json", "table", "plain"],
+        help = "Output format"
+    )]
+    pub format: Format,
 }
 
 #[derive(StructOpt)]
@@ -125,6 +531,15 @@ impl GlobalArgs {
     }
 }
 
+impl StartServerCommand {
+    pub fn state_dir(&self) -> Cow<'_, Path> {
+        self.state_dir
+            .as_ref()
+            .map(Cow::from)
+            .unwrap_or_else(|| Cow::from(default_state_dir()))
+    }
+}
+
 fn default_socket_path() -> PathBuf {
     let uid = unsafe { libc::getuid() };
     let mut path = std::env::temp_dir();
@@ -132,6 +547,21 @@ fn default_socket_path() -> PathBuf {
     path
 }
 
+fn default_state_dir() -> PathBuf {
+    let mut path = std::env::var_os("XDG_STATE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("HOME").map(|home| {
+                let mut path = PathBuf::from(home);
+                path.push(".local/state");
+                path
+            })
+        })
+        .unwrap_or_else(std::env::temp_dir);
+    path.push("pqueue");
+    path
+}
+
 fn parse_log_level(occurrences: u64) -> LevelFilter {
     match occurrences {
         0 => LevelFilter::Off,
@@ -1,14 +1,13 @@
 use std::io;
-use std::path::{Path, PathBuf};
-use std::time::Duration;
+use std::path::Path;
 
 use anyhow::{anyhow, Context, Result};
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::UnixStream;
 
 use crate::connection::Connection;
 use crate::request::{self, Request};
 use crate::response::{self, Response};
-use crate::template::Template;
 
 pub struct QueueClient {
     connection: Connection,
@@ -33,27 +32,11 @@ impl QueueClient {
         Ok(Self { connection })
     }
 
-    pub async fn stop_server(&mut self) -> Result<response::Empty> {
-        self.request(Request::StopServer).await
+    pub async fn stop_server(&mut self, request: request::StopServer) -> Result<response::Empty> {
+        self.request(request).await
     }
 
-    pub async fn create_queue(
-        &mut self,
-        name: String,
-        max_parallel: usize,
-        output: Option<PathBuf>,
-        timeout: Option<Duration>,
-        dir: Option<PathBuf>,
-        template: Option<Template>,
-    ) -> Result<response::Empty> {
-        let request = request::CreateQueue {
-            name,
-            max_parallel,
-            output,
-            timeout,
-            dir,
-            template,
-        };
+    pub async fn create_queue(&mut self, request: request::CreateQueue) -> Result<response::Empty> {
         self.request(request).await
     }
 
@@ -62,31 +45,116 @@ impl QueueClient {
         self.request(request).await
     }
 
-    pub async fn send(
-        &mut self,
-        name: String,
-        timeout: Option<Duration>,
-        dir: Option<PathBuf>,
-        args: Vec<String>,
-    ) -> Result<response::Empty> {
-        let request = request::Send {
-            name,
-            dir,
-            timeout,
-            args,
-        };
+    pub async fn update_queue(&mut self, request: request::UpdateQueue) -> Result<response::Empty> {
+        self.request(request).await
+    }
+
+    pub async fn pause_queue(&mut self, name: String) -> Result<response::Empty> {
+        let request = request::PauseQueue { name };
         self.request(request).await
     }
 
+    pub async fn resume_queue(&mut self, name: String) -> Result<response::Empty> {
+        let request = request::ResumeQueue { name };
+        self.request(request).await
+    }
+
+    pub async fn send(&mut self, request: request::Send) -> Result<response::Send> {
+        self.request(request).await
+    }
+
+    /// Sends a task whose standard input is read from `input`.
+    pub async fn send_with_input<R>(
+        &mut self,
+        request: request::Send,
+        input: &mut R,
+    ) -> Result<response::Send>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let request: Request = request.into();
+        self.connection.write_message(&request).await?;
+        self.connection.write_raw(input).await?;
+        self.read_response().await
+    }
+
     pub async fn list_queues(&mut self) -> Result<response::ListQueues> {
         self.request(Request::ListQueues).await
     }
 
+    pub async fn show_queue(&mut self, name: String) -> Result<response::Queue> {
+        let request = request::ShowQueue { name };
+        self.request(request).await
+    }
+
     pub async fn list_tasks(&mut self, name: String) -> Result<response::ListTasks> {
         let request = request::ListTasks { name };
         self.request(request).await
     }
 
+    pub async fn cancel_task(&mut self, id: u64) -> Result<response::Empty> {
+        let request = request::CancelTask { id };
+        self.request(request).await
+    }
+
+    pub async fn history(&mut self, name: String) -> Result<response::History> {
+        let request = request::History { name };
+        self.request(request).await
+    }
+
+    /// Copies the standard output and standard error of a task to `stdout`
+    /// and `stderr`.
+    pub async fn logs<O, E>(&mut self, id: u64, stdout: &mut O, stderr: &mut E) -> Result<()>
+    where
+        O: AsyncWrite + Unpin,
+        E: AsyncWrite + Unpin,
+    {
+        let _: response::Empty = self.request(request::Logs { id }).await?;
+        self.connection.read_raw(stdout).await?;
+        self.connection.read_raw(stderr).await
+    }
+
+    /// Starts following output of a task, use `read_output` to receive it.
+    pub async fn follow_logs(&mut self, id: u64) -> Result<()> {
+        let request: Request = request::FollowLogs { id }.into();
+        self.connection.write_message(&request).await
+    }
+
+    pub async fn read_output(&mut self) -> Result<response::Output> {
+        self.read_response().await
+    }
+
+    pub async fn add_schedule(
+        &mut self,
+        request: request::AddSchedule,
+    ) -> Result<response::AddSchedule> {
+        self.request(request).await
+    }
+
+    pub async fn list_schedules(&mut self, name: String) -> Result<response::ListSchedules> {
+        let request = request::ListSchedules { name };
+        self.request(request).await
+    }
+
+    pub async fn remove_schedule(&mut self, id: u64) -> Result<response::Empty> {
+        let request = request::RemoveSchedule { id };
+        self.request(request).await
+    }
+
+    pub async fn reload(&mut self) -> Result<response::Reload> {
+        self.request(Request::Reload).await
+    }
+
+    pub async fn wait_task(&mut self, id: u64) -> Result<response::Wait> {
+        let request = request::WaitTask { id };
+        self.request(request).await
+    }
+
+    pub async fn wait_queue(&mut self, name: String) -> Result<response::Wait> {
+        let request = request::WaitQueue { name };
+        self.request(request).await
+    }
+
     async fn request<T, R>(&mut self, request: T) -> Result<R>
     where
         T: Into<Request>,
@@ -94,6 +162,13 @@ impl QueueClient {
     {
         let request: Request = request.into();
         self.connection.write_message(&request).await?;
+        self.read_response().await
+    }
+
+    async fn read_response<R>(&mut self) -> Result<R>
+    where
+        R: serde::de::DeserializeOwned,
+    {
         self.connection
             .read_message::<Response<R>>()
             .await?
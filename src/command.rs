@@ -1,17 +1,51 @@
 use anyhow::Result;
+use serde::Serialize;
+use std::convert::TryInto;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::time::SystemTime;
+use tokio::io::AsyncWrite;
 use tokio::net::UnixListener;
 
 use crate::args::{
-    CreateQueueCommand, GlobalArgs, ListTasksCommand, RemoveQueueCommand, SendTaskCommand,
-    StartServerCommand,
+    AttachCommand, CancelTaskCommand, CheckConfigCommand, CreateQueueCommand, GlobalArgs,
+    HistoryCommand, ListTasksCommand, LogsCommand, PauseQueueCommand, RemoveQueueCommand,
+    ResumeQueueCommand, ScheduleCommand, SendTaskCommand, ShowQueueCommand, StartServerCommand,
+    StopServerCommand, UpdateQueueCommand, WaitCommand,
 };
 use crate::client::QueueClient;
+use crate::config::Config;
 use crate::daemon::{daemonize, Daemonize};
+use crate::duration::format_duration;
+use crate::format::{self, print_json, Format, Table};
+use crate::request::{self, Dependency, Stdin};
+use crate::response::{self, Stream, TaskState};
 use crate::server::QueueServer;
+use crate::time::format_time;
 use crate::utils;
 
+// Daemonized server has a different working directory than the client
+fn absolute(path: PathBuf) -> Result<PathBuf> {
+    Ok(std::env::current_dir()?.join(path))
+}
+
+fn client_dir(disabled: bool) -> Result<Option<PathBuf>> {
+    if disabled {
+        Ok(None)
+    } else {
+        Ok(Some(std::env::current_dir()?))
+    }
+}
+
 pub fn start_server(args: GlobalArgs, command: StartServerCommand) -> Result<()> {
     let mut notifier = None;
+    // Daemonizing changes the working directory
+    let state_dir = std::env::current_dir()?.join(command.state_dir());
+    // Configuration errors are reported before detaching from the terminal
+    let config = match &command.config {
+        Some(path) => Config::load(&absolute(path.clone())?)?,
+        None => Config::default(),
+    };
 
     if !command.foreground {
         notifier = match daemonize()? {
@@ -41,77 +75,571 @@ pub fn start_server(args: GlobalArgs, command: StartServerCommand) -> Result<()>
         let listener = UnixListener::bind(&path)?;
         let _socket = utils::FileRemover::new(path);
 
+        let server = QueueServer::new(listener, state_dir, config).await?;
+
         if let Some(notifier) = notifier {
             notifier.notify()?;
         }
 
-        let server = QueueServer::new(listener)?;
         let shutdown = server.shutdown_notifer();
-        utils::spawn_signal_handler(shutdown);
+        utils::spawn_signal_handler(shutdown)?;
+        // Also installed without a configuration file, as SIGHUP would
+        // otherwise kill the server and leave its tasks running
+        utils::spawn_reload_handler(server.reload_notifier())?;
         server.serve().await
     })
 }
 
-pub async fn stop_server(args: GlobalArgs) -> Result<()> {
+pub async fn stop_server(args: GlobalArgs, command: StopServerCommand) -> Result<()> {
     let path = args.socket();
     let mut client = QueueClient::connect(path).await?;
-    client.stop_server().await?;
-    Ok(())
+    let resp = client
+        .stop_server(request::StopServer {
+            drain: command.drain,
+            queued: command.queued,
+            timeout: command.timeout,
+        })
+        .await?;
+    print_empty(args.format, &resp)
 }
 
 pub async fn create_queue(args: GlobalArgs, command: CreateQueueCommand) -> Result<()> {
     let path = args.socket();
     let mut client = QueueClient::connect(path).await?;
-    client
-        .create_queue(
-            command.name,
-            command.max_parallel,
-            command.file,
-            command.timeout,
-            command.dir,
-            command.template,
-        )
+    let output = if command.stdout { None } else { command.file };
+    let resp = client
+        .create_queue(request::CreateQueue {
+            name: command.name,
+            max_parallel: command.max_parallel,
+            output: output.map(absolute).transpose()?,
+            timeout: command.timeout,
+            dir: command.dir.map(absolute).transpose()?,
+            template: command.template,
+            retry: command.retry.into(),
+            kill: command.kill.into(),
+            env: command.env.try_into()?,
+            history_size: command.history_size,
+        })
         .await?;
-    Ok(())
+    print_empty(args.format, &resp)
 }
 
 pub async fn remove_queue(args: GlobalArgs, command: RemoveQueueCommand) -> Result<()> {
     let path = args.socket();
     let mut client = QueueClient::connect(path).await?;
-    client.remove_queue(command.name).await?;
-    Ok(())
+    let resp = client.remove_queue(command.name).await?;
+    print_empty(args.format, &resp)
 }
 
-pub async fn send(args: GlobalArgs, command: SendTaskCommand) -> Result<()> {
+pub async fn update_queue(args: GlobalArgs, command: UpdateQueueCommand) -> Result<()> {
     let path = args.socket();
     let mut client = QueueClient::connect(path).await?;
-    client
-        .send(command.name, command.timeout, command.dir, command.args)
+    let output = if command.stdout {
+        Some(None)
+    } else {
+        command.file.map(absolute).transpose()?.map(Some)
+    };
+    let dir = if command.no_dir {
+        Some(None)
+    } else {
+        command.dir.map(absolute).transpose()?.map(Some)
+    };
+    let timeout = if command.no_timeout {
+        Some(None)
+    } else {
+        command.timeout.map(Some)
+    };
+    let template = if command.no_template {
+        Some(None)
+    } else {
+        command.template.map(Some)
+    };
+    let resp = client
+        .update_queue(request::UpdateQueue {
+            name: command.name,
+            max_parallel: command.max_parallel,
+            output,
+            timeout,
+            dir,
+            template,
+            retry: None,
+            kill: None,
+            env: None,
+            history_size: None,
+        })
         .await?;
+    print_empty(args.format, &resp)
+}
+
+pub async fn pause_queue(args: GlobalArgs, command: PauseQueueCommand) -> Result<()> {
+    let path = args.socket();
+    let mut client = QueueClient::connect(path).await?;
+    let resp = client.pause_queue(command.name).await?;
+    print_empty(args.format, &resp)
+}
+
+pub async fn resume_queue(args: GlobalArgs, command: ResumeQueueCommand) -> Result<()> {
+    let path = args.socket();
+    let mut client = QueueClient::connect(path).await?;
+    let resp = client.resume_queue(command.name).await?;
+    print_empty(args.format, &resp)
+}
+
+pub async fn send(args: GlobalArgs, command: SendTaskCommand) -> Result<()> {
+    let path = args.socket();
+    let mut client = QueueClient::connect(path).await?;
+    let at = command
+        .at
+        .or_else(|| command.delay.map(|delay| SystemTime::now() + delay));
+    let after = command
+        .after
+        .into_iter()
+        .map(|id| Dependency { id, any: false })
+        .chain(
+            command
+                .after_any
+                .into_iter()
+                .map(|id| Dependency { id, any: true }),
+        )
+        .collect();
+    let stdin = if command.stdin {
+        Stdin::Spool
+    } else if let Some(path) = command.stdin_file {
+        // The file is opened by the server when the task is started
+        Stdin::File(absolute(path)?)
+    } else {
+        Stdin::Null
+    };
+    let request = request::Send {
+        name: command.name,
+        timeout: command.timeout,
+        dir: command.dir.map(absolute).transpose()?,
+        cwd: client_dir(command.no_cwd)?,
+        args: command.args,
+        priority: command.priority,
+        at,
+        after,
+        stdin,
+        retry: command.retry.into(),
+        kill: command.kill.into(),
+        env: command.env.try_into()?,
+    };
+    let resp = if request.stdin == Stdin::Spool {
+        client
+            .send_with_input(request, &mut tokio::io::stdin())
+            .await?
+    } else {
+        client.send(request).await?
+    };
+    print_id(args.format, &resp, resp.id)?;
+    if command.wait {
+        let resp = client.wait_task(resp.id).await?;
+        if args.format == Format::Json {
+            print_json(&resp)?;
+        }
+        exit_with(&resp.tasks);
+    }
     Ok(())
 }
 
 pub async fn list_queues(args: GlobalArgs) -> Result<()> {
     let path = args.socket();
     let mut client = QueueClient::connect(path).await?;
-    for queue in client.list_queues().await?.queues {
-        println!("{}", queue.name);
+    let resp = client.list_queues().await?;
+    format::print(args.format, &resp, || {
+        let mut table = Table::new(&[
+            "NAME",
+            "STATE",
+            "PARALLEL",
+            "QUEUED",
+            "RUNNING",
+            "COMPLETED",
+            "FAILED",
+        ]);
+        for queue in resp.queues.iter() {
+            table.push(vec![
+                queue.name.clone(),
+                queue_state(queue).into(),
+                queue.max_parallel.to_string(),
+                queue.stats.queued.to_string(),
+                queue.stats.running.to_string(),
+                queue.stats.completed.to_string(),
+                queue.stats.failed.to_string(),
+            ]);
+        }
+        table
+    })
+}
+
+pub async fn check_config(args: GlobalArgs, command: CheckConfigCommand) -> Result<()> {
+    let config = Config::load(&absolute(command.path)?)?;
+    format::print(args.format, &config, || {
+        let mut table = Table::new(&["NAME", "PARALLEL", "SCHEDULES"]);
+        for queue in config.queues.iter() {
+            table.push(vec![
+                queue.queue.name.clone(),
+                queue.queue.max_parallel.to_string(),
+                queue.schedules.len().to_string(),
+            ]);
+        }
+        table
+    })
+}
+
+pub async fn reload(args: GlobalArgs) -> Result<()> {
+    let path = args.socket();
+    let mut client = QueueClient::connect(path).await?;
+    let resp = client.reload().await?;
+    format::print(args.format, &resp, || {
+        let mut table = Table::new(&["NAME", "CHANGE"]);
+        let changes = [
+            (&resp.created, "created"),
+            (&resp.updated, "updated"),
+            (&resp.retired, "retired"),
+        ];
+        for (names, change) in changes {
+            for name in names.iter() {
+                table.push(vec![name.clone(), change.into()]);
+            }
+        }
+        table
+    })
+}
+
+pub async fn show_queue(args: GlobalArgs, command: ShowQueueCommand) -> Result<()> {
+    let path = args.socket();
+    let mut client = QueueClient::connect(path).await?;
+    let queue = client.show_queue(command.name).await?;
+    format::print(args.format, &queue, || {
+        let optional = |value: Option<String>| value.unwrap_or_else(|| "-".into());
+        let vars: Vec<String> = queue
+            .env
+            .vars
+            .iter()
+            .map(|(key, value)| format!("{}={}", key, value))
+            .collect();
+        let rows = vec![
+            ("name", queue.name.clone()),
+            ("state", queue_state(&queue).into()),
+            ("max_parallel", queue.max_parallel.to_string()),
+            (
+                "output",
+                queue
+                    .output
+                    .as_ref()
+                    .map_or_else(|| "stdout".into(), |path| path.display().to_string()),
+            ),
+            ("timeout", optional(queue.timeout.map(format_duration))),
+            (
+                "dir",
+                optional(queue.dir.as_ref().map(|dir| dir.display().to_string())),
+            ),
+            ("template", optional(queue.template.clone())),
+            (
+                "max_attempts",
+                optional(
+                    queue
+                        .retry
+                        .max_attempts
+                        .map(|attempts| attempts.to_string()),
+                ),
+            ),
+            (
+                "retry_delay",
+                optional(queue.retry.delay.map(format_duration)),
+            ),
+            (
+                "retry_backoff",
+                optional(queue.retry.backoff.map(|backoff| backoff.to_string())),
+            ),
+            (
+                "retry_on",
+                optional(Some(&queue.retry.on).filter(|on| !on.is_empty()).map(|on| {
+                    on.iter()
+                        .map(|on| on.to_string())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                })),
+            ),
+            ("kill_signal", queue.kill.signal().to_string()),
+            ("kill_grace", format_duration(queue.kill.grace())),
+            (
+                "env",
+                optional(Some(quote(&vars)).filter(|vars| !vars.is_empty())),
+            ),
+            ("clear_env", queue.env.clear.unwrap_or(false).to_string()),
+            ("history_size", queue.history_size.to_string()),
+            ("queued", queue.stats.queued.to_string()),
+            ("running", queue.stats.running.to_string()),
+            ("completed", queue.stats.completed.to_string()),
+            ("failed", queue.stats.failed.to_string()),
+            (
+                "average_runtime",
+                optional(queue.stats.average_runtime.map(format_duration)),
+            ),
+            ("uptime", format_duration(queue.stats.uptime)),
+        ];
+        let mut table = Table::new(&[]);
+        for (key, value) in rows {
+            table.push(vec![key.into(), value]);
+        }
+        table
+    })
+}
+
+pub async fn list_tasks(args: GlobalArgs, command: ListTasksCommand) -> Result<()> {
+    let path = args.socket();
+    let mut client = QueueClient::connect(path).await?;
+    let resp = client.list_tasks(command.name).await?;
+    format::print(args.format, &resp, || {
+        let mut table = Table::new(&[
+            "ID", "STATE", "PRIORITY", "ATTEMPT", "PID", "TIME", "ELAPSED", "COMMAND",
+        ]);
+        for task in resp.tasks.iter() {
+            table.push(vec![
+                task.id.to_string(),
+                task.state.to_string(),
+                task.priority.to_string(),
+                task.attempt.to_string(),
+                task.pid.map_or_else(|| "-".into(), |pid| pid.to_string()),
+                task.started
+                    .or(task.scheduled)
+                    .map_or_else(|| "-".into(), format_time),
+                task.elapsed.map_or_else(|| "-".into(), format_duration),
+                quote(&task.args),
+            ]);
+        }
+        table
+    })
+}
+
+pub async fn cancel_task(args: GlobalArgs, command: CancelTaskCommand) -> Result<()> {
+    let path = args.socket();
+    let mut client = QueueClient::connect(path).await?;
+    let resp = client.cancel_task(command.id).await?;
+    print_empty(args.format, &resp)
+}
+
+pub async fn history(args: GlobalArgs, command: HistoryCommand) -> Result<()> {
+    let path = args.socket();
+    let mut client = QueueClient::connect(path).await?;
+    let resp = client.history(command.name).await?;
+    format::print(args.format, &resp, || {
+        let mut table = Table::new(&[
+            "ID", "STATE", "ATTEMPTS", "EXIT", "STARTED", "ENDED", "COMMAND",
+        ]);
+        for task in resp.tasks.iter() {
+            let exit = match (task.exit_code, task.signal) {
+                (Some(code), _) => code.to_string(),
+                (_, Some(signal)) => format!("signal {}", signal),
+                _ => "-".into(),
+            };
+            table.push(vec![
+                task.id.to_string(),
+                task.state.to_string(),
+                task.attempts.to_string(),
+                exit,
+                task.started.map_or_else(|| "-".into(), format_time),
+                format_time(task.ended),
+                quote(&task.args),
+            ]);
+        }
+        table
+    })
+}
+
+#[derive(Serialize)]
+struct Logs {
+    stdout: String,
+    stderr: String,
+}
+
+pub async fn logs(args: GlobalArgs, command: LogsCommand) -> Result<()> {
+    let path = args.socket();
+    let mut client = QueueClient::connect(path).await?;
+    if command.follow {
+        return follow(
+            client,
+            args.format,
+            command.id,
+            !command.stderr,
+            !command.stdout,
+        )
+        .await;
+    }
+    if args.format == Format::Json {
+        let (mut stdout, mut stderr) = (vec![], vec![]);
+        client.logs(command.id, &mut stdout, &mut stderr).await?;
+        return print_json(&Logs {
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        });
+    }
+    // Output is streamed, unwanted stream is still received but discarded
+    let mut stdout: Box<dyn AsyncWrite + Unpin> = if command.stderr {
+        Box::new(tokio::io::sink())
+    } else {
+        Box::new(tokio::io::stdout())
+    };
+    let mut stderr: Box<dyn AsyncWrite + Unpin> = if command.stdout {
+        Box::new(tokio::io::sink())
+    } else {
+        Box::new(tokio::io::stderr())
+    };
+    client.logs(command.id, &mut stdout, &mut stderr).await
+}
+
+pub async fn attach(args: GlobalArgs, command: AttachCommand) -> Result<()> {
+    let path = args.socket();
+    let client = QueueClient::connect(path).await?;
+    follow(client, args.format, command.id, true, true).await
+}
+
+async fn follow(
+    mut client: QueueClient,
+    format: Format,
+    id: u64,
+    stdout: bool,
+    stderr: bool,
+) -> Result<()> {
+    client.follow_logs(id).await?;
+    loop {
+        let output = client.read_output().await?;
+        if format == Format::Json {
+            print_json(&output)?;
+            if let response::Output::End = output {
+                return Ok(());
+            }
+            continue;
+        }
+        match output {
+            response::Output::Data {
+                stream: Stream::Stdout,
+                data,
+            } if stdout => {
+                io::stdout().write_all(data.as_bytes())?;
+                io::stdout().flush()?;
+            }
+            response::Output::Data {
+                stream: Stream::Stderr,
+                data,
+            } if stderr => io::stderr().write_all(data.as_bytes())?,
+            response::Output::Data { .. } => {}
+            response::Output::End => return Ok(()),
+        }
+    }
+}
+
+pub async fn schedule(args: GlobalArgs, command: ScheduleCommand) -> Result<()> {
+    let path = args.socket();
+    let mut client = QueueClient::connect(path).await?;
+    match command {
+        ScheduleCommand::Add(add) => {
+            let resp = client
+                .add_schedule(request::AddSchedule {
+                    name: add.name,
+                    when: add.when,
+                    skip_if_running: add.skip_if_running,
+                    timeout: add.timeout,
+                    dir: add.dir.map(absolute).transpose()?,
+                    cwd: client_dir(add.no_cwd)?,
+                    args: add.args,
+                    priority: add.priority,
+                    retry: add.retry.into(),
+                    kill: add.kill.into(),
+                    env: add.env.try_into()?,
+                })
+                .await?;
+            print_id(args.format, &resp, resp.id)?;
+        }
+        ScheduleCommand::List(list) => {
+            let resp = client.list_schedules(list.name).await?;
+            format::print(args.format, &resp, || {
+                let mut table = Table::new(&["ID", "WHEN", "NEXT", "SKIP", "COMMAND"]);
+                for schedule in resp.schedules.iter() {
+                    let skip = if schedule.skip_if_running {
+                        "skip"
+                    } else {
+                        "-"
+                    };
+                    table.push(vec![
+                        schedule.id.to_string(),
+                        schedule.when.clone(),
+                        schedule.next.map_or_else(|| "-".into(), format_time),
+                        skip.into(),
+                        quote(&schedule.args),
+                    ]);
+                }
+                table
+            })?;
+        }
+        ScheduleCommand::Remove(remove) => {
+            let resp = client.remove_schedule(remove.id).await?;
+            print_empty(args.format, &resp)?;
+        }
     }
     Ok(())
 }
 
-pub async fn list_tasks(args: GlobalArgs, command: ListTasksCommand) -> Result<()> {
+pub async fn wait(args: GlobalArgs, command: WaitCommand) -> Result<()> {
     let path = args.socket();
     let mut client = QueueClient::connect(path).await?;
-    for task in client.list_tasks(command.name).await?.tasks {
-        println!(
-            "{}",
-            task.args
-                .iter()
-                .map(|s| shlex::quote(s))
-                .collect::<Vec<_>>()
-                .join(" ")
-        );
+    let resp = match command.id {
+        Some(id) => client.wait_task(id).await?,
+        None => client.wait_queue(command.name).await?,
+    };
+    if args.format == Format::Json {
+        print_json(&resp)?;
     }
+    exit_with(&resp.tasks);
     Ok(())
 }
+
+fn queue_state(queue: &response::Queue) -> &'static str {
+    if queue.retiring {
+        "retiring"
+    } else if queue.paused {
+        "paused"
+    } else {
+        "active"
+    }
+}
+
+fn quote(args: &[String]) -> String {
+    args.iter()
+        .map(|arg| shlex::quote(arg))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Prints `{}` as JSON, so that every command has parseable output.
+fn print_empty(format: Format, resp: &response::Empty) -> Result<()> {
+    format::print(format, resp, || Table::new(&[]))
+}
+
+fn print_id<T>(format: Format, resp: &T, id: u64) -> Result<()>
+where
+    T: Serialize,
+{
+    format::print(format, resp, || {
+        let mut table = Table::new(&[]);
+        table.push(vec![id.to_string()]);
+        table
+    })
+}
+
+fn exit_with(tasks: &[response::FinishedTask]) {
+    let code = tasks
+        .iter()
+        .map(|task| match (task.exit_code, task.signal) {
+            (Some(code), _) => code,
+            (_, Some(signal)) => 128 + signal,
+            _ if task.state == TaskState::Finished => 0,
+            _ => 1,
+        })
+        .find(|code| *code != 0)
+        .unwrap_or(0);
+    if code != 0 {
+        std::process::exit(code);
+    }
+}
@@ -0,0 +1,296 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use anyhow::{anyhow, bail, Result};
+use serde::de;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::cron::Recurrence;
+use crate::duration::parse_duration;
+use crate::env::Environment;
+use crate::kill::{KillPolicy, Signal};
+use crate::request;
+use crate::retry::{Backoff, RetryOn, RetryPolicy};
+use crate::template::Template;
+
+/// Queues and recurring tasks created when the server starts.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Config {
+    /// File the configuration was loaded from
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    pub queues: Vec<QueueConfig>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct QueueConfig {
+    #[serde(flatten)]
+    pub queue: request::CreateQueue,
+    pub schedules: Vec<request::AddSchedule>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct ConfigFile {
+    defaults: QueueSection,
+    queues: BTreeMap<String, QueueSection>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct QueueSection {
+    max_parallel: Option<usize>,
+    output: Option<PathBuf>,
+    dir: Option<PathBuf>,
+    #[serde(deserialize_with = "duration")]
+    timeout: Option<Duration>,
+    template: Option<Template>,
+    max_attempts: Option<u32>,
+    #[serde(deserialize_with = "duration")]
+    retry_delay: Option<Duration>,
+    retry_backoff: Option<Backoff>,
+    #[serde(deserialize_with = "retry_on")]
+    retry_on: Vec<RetryOn>,
+    kill_signal: Option<Signal>,
+    #[serde(deserialize_with = "duration")]
+    kill_grace: Option<Duration>,
+    env: BTreeMap<String, String>,
+    clear_env: Option<bool>,
+    history_size: Option<usize>,
+    schedules: Vec<ScheduleSection>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduleSection {
+    when: Recurrence,
+    command: Vec<String>,
+    #[serde(default)]
+    dir: Option<PathBuf>,
+    #[serde(default, deserialize_with = "duration")]
+    timeout: Option<Duration>,
+    #[serde(default)]
+    priority: i32,
+    #[serde(default)]
+    skip_if_running: bool,
+    #[serde(default)]
+    max_attempts: Option<u32>,
+    #[serde(default, deserialize_with = "duration")]
+    retry_delay: Option<Duration>,
+    #[serde(default)]
+    retry_backoff: Option<Backoff>,
+    #[serde(default, deserialize_with = "retry_on")]
+    retry_on: Vec<RetryOn>,
+    #[serde(default)]
+    kill_signal: Option<Signal>,
+    #[serde(default, deserialize_with = "duration")]
+    kill_grace: Option<Duration>,
+    #[serde(default)]
+    env: BTreeMap<String, String>,
+    #[serde(default)]
+    clear_env: Option<bool>,
+}
+
+fn duration<'de, D>(deserializer: D) -> Result<Option<Duration>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let s = String::deserialize(deserializer)?;
+    parse_duration(&s).map(Some).map_err(de::Error::custom)
+}
+
+fn retry_on<'de, D>(deserializer: D) -> Result<Vec<RetryOn>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+impl QueueSection {
+    fn or(self, defaults: &QueueSection) -> QueueSection {
+        let mut env = defaults.env.clone();
+        env.extend(self.env);
+        QueueSection {
+            max_parallel: self.max_parallel.or(defaults.max_parallel),
+            output: self.output.or_else(|| defaults.output.clone()),
+            dir: self.dir.or_else(|| defaults.dir.clone()),
+            timeout: self.timeout.or(defaults.timeout),
+            template: self.template.or_else(|| defaults.template.clone()),
+            max_attempts: self.max_attempts.or(defaults.max_attempts),
+            retry_delay: self.retry_delay.or(defaults.retry_delay),
+            retry_backoff: self.retry_backoff.or(defaults.retry_backoff),
+            retry_on: if self.retry_on.is_empty() {
+                defaults.retry_on.clone()
+            } else {
+                self.retry_on
+            },
+            kill_signal: self.kill_signal.or(defaults.kill_signal),
+            kill_grace: self.kill_grace.or(defaults.kill_grace),
+            env,
+            clear_env: self.clear_env.or(defaults.clear_env),
+            history_size: self.history_size.or(defaults.history_size),
+            schedules: self.schedules,
+        }
+    }
+
+    fn build(self, name: String, base: &Path) -> Result<QueueConfig> {
+        let queue = request::CreateQueue {
+            name: name.clone(),
+            max_parallel: self.max_parallel.unwrap_or(1),
+            output: self.output.map(|path| base.join(path)),
+            timeout: self.timeout,
+            dir: self.dir.map(|dir| base.join(dir)),
+            template: self.template,
+            retry: RetryPolicy {
+                max_attempts: self.max_attempts,
+                delay: self.retry_delay,
+                backoff: self.retry_backoff,
+                on: self.retry_on,
+            },
+            kill: KillPolicy {
+                signal: self.kill_signal,
+                grace: self.kill_grace,
+            },
+            env: Environment {
+                vars: self.env,
+                clear: self.clear_env,
+            },
+            history_size: self.history_size.unwrap_or(request::DEFAULT_HISTORY_SIZE),
+        };
+        let mut schedules = vec![];
+        for (index, schedule) in self.schedules.into_iter().enumerate() {
+            let args = match &queue.template {
+                Some(template) => template.instantiate(schedule.command.clone()),
+                None => Ok(schedule.command.clone()),
+            }
+            .map_err(|err| anyhow!("queue '{}', schedule {}: {}", name, index + 1, err))?;
+            if args.is_empty() {
+                bail!(
+                    "queue '{}', schedule {}: command cannot be empty",
+                    name,
+                    index + 1
+                );
+            }
+            schedules.push(request::AddSchedule {
+                name: name.clone(),
+                when: schedule.when,
+                skip_if_running: schedule.skip_if_running,
+                timeout: schedule.timeout,
+                dir: schedule.dir.map(|dir| base.join(dir)),
+                cwd: None,
+                args: schedule.command,
+                priority: schedule.priority,
+                retry: RetryPolicy {
+                    max_attempts: schedule.max_attempts,
+                    delay: schedule.retry_delay,
+                    backoff: schedule.retry_backoff,
+                    on: schedule.retry_on,
+                },
+                kill: KillPolicy {
+                    signal: schedule.kill_signal,
+                    grace: schedule.kill_grace,
+                },
+                env: Environment {
+                    vars: schedule.env,
+                    clear: schedule.clear_env,
+                },
+            });
+        }
+        Ok(QueueConfig { queue, schedules })
+    }
+}
+
+impl Config {
+    /// Reads the configuration file at `path`. Relative paths in the file are
+    /// resolved against the directory containing it.
+    pub fn load(path: &Path) -> Result<Config> {
+        let input = std::fs::read_to_string(path)
+            .map_err(|err| anyhow!("cannot read '{}': {}", path.display(), err))?;
+        let base = path.parent().unwrap_or_else(|| Path::new("/"));
+        let config =
+            Config::parse(&input, base).map_err(|err| anyhow!("{}: {}", path.display(), err))?;
+        Ok(Config {
+            path: Some(path.to_path_buf()),
+            ..config
+        })
+    }
+
+    fn parse(input: &str, base: &Path) -> Result<Config> {
+        let ConfigFile { defaults, queues } = toml::from_str(input)?;
+        if !defaults.schedules.is_empty() {
+            bail!("schedules cannot be set in defaults");
+        }
+        let queues = queues
+            .into_iter()
+            .map(|(name, section)| section.or(&defaults).build(name, base))
+            .collect::<Result<_>>()?;
+        Ok(Config { path: None, queues })
+    }
+}
+
+#[test]
+fn test_parse_config() {
+    let config = Config::parse(
+        r#"
+        [defaults]
+        timeout = "1h"
+        max_attempts = 3
+        env = { A = "1", B = "2" }
+
+        [queues.build]
+        max_parallel = 4
+        dir = "src"
+        retry_on = ["timeout", "3"]
+        env = { B = "3" }
+
+        [queues.fetch]
+        template = "curl -O {}"
+        output = "/var/log/fetch.log"
+
+        [[queues.fetch.schedules]]
+        when = "every 10m"
+        command = ["https://example.com"]
+        skip_if_running = true
+        "#,
+        Path::new("/etc/pqueue"),
+    )
+    .unwrap();
+
+    let build = &config.queues[0];
+    assert_eq!(build.queue.name, "build");
+    assert_eq!(build.queue.max_parallel, 4);
+    assert_eq!(build.queue.dir, Some(PathBuf::from("/etc/pqueue/src")));
+    assert_eq!(build.queue.timeout, Some(Duration::from_secs(3600)));
+    assert_eq!(build.queue.retry.max_attempts, Some(3));
+    assert_eq!(
+        build.queue.retry.on,
+        vec![RetryOn::Timeout, RetryOn::ExitCode(3)]
+    );
+    assert_eq!(build.queue.env.vars["A"], "1");
+    assert_eq!(build.queue.env.vars["B"], "3");
+    assert_eq!(build.queue.history_size, request::DEFAULT_HISTORY_SIZE);
+    assert!(build.schedules.is_empty());
+
+    let fetch = &config.queues[1];
+    assert_eq!(fetch.queue.max_parallel, 1);
+    assert_eq!(
+        fetch.queue.output,
+        Some(PathBuf::from("/var/log/fetch.log"))
+    );
+    assert_eq!(fetch.schedules.len(), 1);
+    assert_eq!(fetch.schedules[0].name, "fetch");
+    assert!(fetch.schedules[0].skip_if_running);
+
+    let base = Path::new("/");
+    assert!(Config::parse("[queues.a]\nmax_paralel = 2", base).is_err());
+    assert!(Config::parse("[queues.a]\ntimeout = \"soon\"", base).is_err());
+    assert!(Config::parse(
+        "[queues.a]\ntemplate = \"echo {}\"\n[[queues.a.schedules]]\nwhen = \"every 1m\"\ncommand = []",
+        base
+    )
+    .is_err());
+}
@@ -1,8 +1,10 @@
 use anyhow::{anyhow, Result};
 use bytes::{Buf, BytesMut};
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::UnixStream;
 
+const RAW_BUFFER_SIZE: usize = 64 * 1024;
+
 pub struct Connection {
     socket: UnixStream,
     buffer: BytesMut,
@@ -44,6 +46,61 @@ impl Connection {
         self.socket.write_u8(b'\0').await?;
         Ok(())
     }
+
+    /// Reads raw data written by `write_raw` into `writer`. Data is consumed
+    /// up to the end marker even if writing fails so that the connection can
+    /// still be used.
+    pub async fn read_raw<W>(&mut self, writer: &mut W) -> Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let mut ret = Ok(());
+        loop {
+            self.fill(4).await?;
+            let mut remaining = self.buffer.get_u32() as usize;
+            if remaining == 0 {
+                break;
+            }
+            while remaining > 0 {
+                self.fill(1).await?;
+                let count = remaining.min(self.buffer.len());
+                if ret.is_ok() {
+                    ret = writer.write_all(&self.buffer[..count]).await;
+                }
+                self.buffer.advance(count);
+                remaining -= count;
+            }
+        }
+        ret?;
+        writer.flush().await?;
+        Ok(())
+    }
+
+    /// Writes data from `reader` as length-prefixed chunks followed by an
+    /// empty chunk marking the end.
+    pub async fn write_raw<R>(&mut self, reader: &mut R) -> Result<()>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut buffer = vec![0; RAW_BUFFER_SIZE];
+        loop {
+            let count = reader.read(&mut buffer).await?;
+            self.socket.write_u32(count as u32).await?;
+            if count == 0 {
+                return Ok(());
+            }
+            self.socket.write_all(&buffer[..count]).await?;
+        }
+    }
+
+    async fn fill(&mut self, size: usize) -> Result<()> {
+        while self.buffer.len() < size {
+            if self.socket.read_buf(&mut self.buffer).await? == 0 {
+                return Err(anyhow!("disconnect"));
+            }
+        }
+        Ok(())
+    }
 }
 
 fn parse_message<M>(buffer: &[u8]) -> Result<Option<(M, usize)>>
@@ -57,3 +114,30 @@ where
         Ok(None)
     }
 }
+
+#[tokio::test]
+async fn test_raw() {
+    let (a, b) = UnixStream::pair().unwrap();
+    let (mut a, mut b) = (Connection::new(a), Connection::new(b));
+    let data = (0..RAW_BUFFER_SIZE * 2 + 1)
+        .map(|i| i as u8)
+        .collect::<Vec<_>>();
+    let input = data.clone();
+    let writer = tokio::spawn(async move {
+        a.write_message(&"before").await.unwrap();
+        a.write_raw(&mut &input[..]).await.unwrap();
+        a.write_message(&"after").await.unwrap();
+    });
+    let mut output = vec![];
+    assert_eq!(
+        b.read_message::<String>().await.unwrap().as_deref(),
+        Some("before")
+    );
+    b.read_raw(&mut output).await.unwrap();
+    assert_eq!(output, data);
+    assert_eq!(
+        b.read_message::<String>().await.unwrap().as_deref(),
+        Some("after")
+    );
+    writer.await.unwrap();
+}
@@ -0,0 +1,303 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Error, Result};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+use crate::duration::{format_duration, parse_duration};
+use crate::ffi;
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+// Upper bound for the search of the next matching time. Expressions such as
+// "0 0 30 2 *" never match.
+const MAX_STEPS: usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Field {
+    bits: u64,
+    // Set if the field was not "*"
+    restricted: bool,
+}
+
+impl Field {
+    fn contains(&self, value: i32) -> bool {
+        self.bits & (1 << value) != 0
+    }
+}
+
+fn parse_value(input: &str, names: &[&str], offset: u32) -> Result<u32> {
+    if let Some(index) = names
+        .iter()
+        .position(|name| input.eq_ignore_ascii_case(name))
+    {
+        return Ok(index as u32 + offset);
+    }
+    input
+        .parse()
+        .map_err(|_| anyhow!("invalid value '{}'", input))
+}
+
+fn parse_field(input: &str, min: u32, max: u32, names: &[&str]) -> Result<Field> {
+    let mut bits = 0;
+    for item in input.split(',') {
+        let (range, step) = match item.find('/') {
+            Some(index) => {
+                let step: u32 = item[index + 1..]
+                    .parse()
+                    .map_err(|_| anyhow!("invalid step in '{}'", item))?;
+                if step == 0 {
+                    bail!("invalid step in '{}'", item);
+                }
+                (&item[..index], step)
+            }
+            None => (item, 1),
+        };
+        let (start, end) = if range == "*" {
+            (min, max)
+        } else if let Some(index) = range.find('-') {
+            (
+                parse_value(&range[..index], names, min)?,
+                parse_value(&range[index + 1..], names, min)?,
+            )
+        } else {
+            let value = parse_value(range, names, min)?;
+            // "5/10" means every 10th value starting from 5
+            (value, if step > 1 { max } else { value })
+        };
+        if start < min || end > max || start > end {
+            bail!("value out of range in '{}'", item);
+        }
+        for value in (start..=end).step_by(step as usize) {
+            bits |= 1 << value;
+        }
+    }
+    Ok(Field {
+        bits,
+        restricted: input != "*",
+    })
+}
+
+/// Cron expression with minute, hour, day of month, month and day of week
+/// fields evaluated in local time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cron {
+    source: String,
+    minutes: Field,
+    hours: Field,
+    days: Field,
+    months: Field,
+    weekdays: Field,
+}
+
+impl Cron {
+    fn matches_day(&self, tm: &libc::tm) -> bool {
+        let day = self.days.contains(tm.tm_mday);
+        let weekday = self.weekdays.contains(tm.tm_wday);
+        // Like in cron, restricting both fields matches either of them
+        if self.days.restricted && self.weekdays.restricted {
+            day || weekday
+        } else {
+            day && weekday
+        }
+    }
+
+    /// Returns the first matching time after `time`.
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs() / 60 * 60 + 60;
+        let mut tm = ffi::localtime(secs as libc::time_t).ok()?;
+        for _ in 0..MAX_STEPS {
+            if !self.months.contains(tm.tm_mon + 1) {
+                tm.tm_mon += 1;
+                tm.tm_mday = 1;
+                tm.tm_hour = 0;
+                tm.tm_min = 0;
+            } else if !self.matches_day(&tm) {
+                tm.tm_mday += 1;
+                tm.tm_hour = 0;
+                tm.tm_min = 0;
+            } else if !self.hours.contains(tm.tm_hour) {
+                tm.tm_hour += 1;
+                tm.tm_min = 0;
+            } else if !self.minutes.contains(tm.tm_min) {
+                tm.tm_min += 1;
+            } else {
+                let secs = ffi::mktime(&mut tm).ok()?;
+                return Some(UNIX_EPOCH + Duration::from_secs(secs as u64));
+            }
+            tm.tm_isdst = -1;
+            ffi::mktime(&mut tm).ok()?;
+        }
+        None
+    }
+}
+
+impl FromStr for Cron {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        let expanded = match input.trim() {
+            "@yearly" | "@annually" => "0 0 1 1 *",
+            "@monthly" => "0 0 1 * *",
+            "@weekly" => "0 0 * * 0",
+            "@daily" | "@midnight" => "0 0 * * *",
+            "@hourly" => "0 * * * *",
+            other => other,
+        };
+        let fields: Vec<&str> = expanded.split_whitespace().collect();
+        if fields.len() != 5 {
+            bail!("cron expression '{}' should have 5 fields", input);
+        }
+        let mut weekdays = parse_field(fields[4], 0, 7, WEEKDAYS)?;
+        // Both 0 and 7 refer to Sunday
+        if weekdays.contains(7) {
+            weekdays.bits |= 1;
+        }
+        Ok(Cron {
+            source: input.trim().to_string(),
+            minutes: parse_field(fields[0], 0, 59, &[])?,
+            hours: parse_field(fields[1], 0, 23, &[])?,
+            days: parse_field(fields[2], 1, 31, &[])?,
+            months: parse_field(fields[3], 1, 12, MONTHS)?,
+            weekdays,
+        })
+    }
+}
+
+/// When a recurring task is started, either a cron expression or a fixed
+/// interval written as "every <duration>".
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recurrence {
+    Cron(Cron),
+    Every(Duration),
+}
+
+impl Recurrence {
+    pub fn next_after(&self, time: SystemTime) -> Option<SystemTime> {
+        match self {
+            Recurrence::Cron(cron) => cron.next_after(time),
+            Recurrence::Every(interval) => Some(time + *interval),
+        }
+    }
+}
+
+impl FromStr for Recurrence {
+    type Err = Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        let input = input.trim();
+        if let Some(interval) = input.strip_prefix("every ") {
+            let interval = parse_duration(interval.trim())?;
+            if interval == Duration::from_secs(0) {
+                bail!("interval cannot be zero");
+            }
+            Ok(Recurrence::Every(interval))
+        } else {
+            Ok(Recurrence::Cron(input.parse()?))
+        }
+    }
+}
+
+impl fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Recurrence::Cron(cron) => write!(f, "{}", cron.source),
+            Recurrence::Every(interval) => write!(f, "every {}", format_duration(*interval)),
+        }
+    }
+}
+
+impl Serialize for Recurrence {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Recurrence {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[test]
+fn test_parse_field() {
+    let bits = |values: &[u32]| values.iter().fold(0, |bits, value| bits | 1 << value);
+    assert_eq!(
+        parse_field("*", 0, 5, &[]).unwrap().bits,
+        bits(&[0, 1, 2, 3, 4, 5])
+    );
+    assert!(!parse_field("*", 0, 5, &[]).unwrap().restricted);
+    assert_eq!(
+        parse_field("1,3-4", 0, 5, &[]).unwrap().bits,
+        bits(&[1, 3, 4])
+    );
+    assert_eq!(
+        parse_field("*/2", 0, 5, &[]).unwrap().bits,
+        bits(&[0, 2, 4])
+    );
+    assert_eq!(
+        parse_field("1/2", 0, 5, &[]).unwrap().bits,
+        bits(&[1, 3, 5])
+    );
+    assert_eq!(
+        parse_field("mon-wed", 0, 7, WEEKDAYS).unwrap().bits,
+        bits(&[1, 2, 3])
+    );
+    assert_eq!(parse_field("feb", 1, 12, MONTHS).unwrap().bits, bits(&[2]));
+    assert!(parse_field("6", 0, 5, &[]).is_err());
+    assert!(parse_field("3-1", 0, 5, &[]).is_err());
+    assert!(parse_field("*/0", 0, 5, &[]).is_err());
+    assert!(parse_field("x", 0, 5, &[]).is_err());
+}
+
+#[test]
+fn test_next_after() {
+    ffi::use_utc();
+    let time = |secs| UNIX_EPOCH + Duration::from_secs(secs);
+    // 2021-03-04 12:00:00
+    let now = time(1614859200);
+
+    let cron: Cron = "30 2 * * *".parse().unwrap();
+    let next = cron.next_after(now).unwrap();
+    // 2021-03-05 02:30:00
+    assert_eq!(next, time(1614911400));
+    assert_eq!(cron.next_after(next - Duration::from_secs(1)), Some(next));
+    assert_eq!(cron.next_after(next), Some(time(1614911400 + 24 * 60 * 60)));
+
+    // 2021-04-01 00:00:00
+    let cron: Cron = "@monthly".parse().unwrap();
+    assert_eq!(cron.next_after(now), Some(time(1617235200)));
+
+    let cron: Cron = "0 0 30 2 *".parse().unwrap();
+    assert_eq!(cron.next_after(now), None);
+}
+
+#[test]
+fn test_recurrence() {
+    assert_eq!(
+        "every 15m".parse::<Recurrence>().unwrap(),
+        Recurrence::Every(Duration::from_secs(15 * 60))
+    );
+    assert_eq!(
+        "every 1h 30m".parse::<Recurrence>().unwrap().to_string(),
+        "every 1h 30m"
+    );
+    assert_eq!(
+        "@daily".parse::<Recurrence>().unwrap().to_string(),
+        "@daily"
+    );
+    assert!("every 0s".parse::<Recurrence>().is_err());
+    assert!("* * *".parse::<Recurrence>().is_err());
+}
@@ -28,7 +28,7 @@ pub struct ReadinessNotifier {
 
 impl ReadinessNotifier {
     pub fn notify(mut self) -> io::Result<()> {
-        self.writer.write_all(&[b'\n'])
+        self.writer.write_all(b"\n")
     }
 }
 
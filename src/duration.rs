@@ -76,6 +76,22 @@ pub fn parse_duration(input: &str) -> Result<Duration> {
     }
 }
 
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    let (hours, minutes, seconds) = (secs / 3600, secs / 60 % 60, secs % 60);
+    let mut pieces = vec![];
+    if hours > 0 {
+        pieces.push(format!("{}h", hours));
+    }
+    if minutes > 0 {
+        pieces.push(format!("{}m", minutes));
+    }
+    if seconds > 0 || pieces.is_empty() {
+        pieces.push(format!("{}s", seconds));
+    }
+    pieces.join(" ")
+}
+
 #[test]
 fn test_separate_numbers() {
     assert_eq!(
@@ -108,3 +124,15 @@ fn test_duration() {
     );
     assert!(parse_duration("10").is_err(),);
 }
+
+#[test]
+fn test_format_duration() {
+    assert_eq!(format_duration(Duration::from_secs(0)), "0s");
+    assert_eq!(format_duration(Duration::from_millis(1500)), "1s");
+    assert_eq!(format_duration(Duration::from_secs(60)), "1m");
+    assert_eq!(format_duration(Duration::from_secs(3723)), "1h 2m 3s");
+    assert_eq!(
+        parse_duration(&format_duration(Duration::from_secs(7201))).unwrap(),
+        Duration::from_secs(7201)
+    );
+}
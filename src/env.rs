@@ -0,0 +1,63 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+
+/// Environment of a task, `vars` are added to the environment of the server
+/// unless `clear` is set. `clear` of a task overrides the queue's when set.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Environment {
+    #[serde(default)]
+    pub vars: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clear: Option<bool>,
+}
+
+impl Environment {
+    pub fn or(self, defaults: &Environment) -> Environment {
+        let mut vars = defaults.vars.clone();
+        vars.extend(self.vars);
+        Environment {
+            vars,
+            clear: self.clear.or(defaults.clear),
+        }
+    }
+
+    pub fn apply(&self, command: &mut Command) {
+        if self.clear.unwrap_or(false) {
+            command.env_clear();
+        }
+        command.envs(&self.vars);
+    }
+}
+
+#[test]
+fn test_environment() {
+    let vars = |pairs: &[(&str, &str)]| -> BTreeMap<String, String> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    };
+    let defaults = Environment {
+        vars: vars(&[("A", "1"), ("B", "2")]),
+        clear: Some(true),
+    };
+    let merged = Environment {
+        vars: vars(&[("B", "3"), ("C", "4")]),
+        clear: None,
+    }
+    .or(&defaults);
+    assert_eq!(merged.vars, vars(&[("A", "1"), ("B", "3"), ("C", "4")]));
+    assert_eq!(merged.clear, Some(true));
+    let inherited = Environment {
+        vars: BTreeMap::new(),
+        clear: Some(false),
+    }
+    .or(&defaults);
+    assert_eq!(inherited.clear, Some(false));
+    assert_eq!(
+        Environment::default().or(&Environment::default()),
+        Environment::default()
+    );
+}
@@ -47,6 +47,22 @@ pub fn write(fd: RawFd, buffer: &[u8]) -> io::Result<usize> {
     )
 }
 
+pub fn localtime(time: libc::time_t) -> io::Result<libc::tm> {
+    let mut tm = MaybeUninit::<libc::tm>::uninit();
+    if unsafe { libc::localtime_r(&time, tm.as_mut_ptr()) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(unsafe { tm.assume_init() })
+}
+
+pub fn mktime(tm: &mut libc::tm) -> io::Result<libc::time_t> {
+    let time = unsafe { libc::mktime(tm) };
+    if time == -1 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid time"));
+    }
+    Ok(time)
+}
+
 pub struct Pipe {
     pub reader: RawFd,
     pub writer: RawFd,
@@ -61,6 +77,16 @@ pub fn pipe() -> io::Result<Pipe> {
     })
 }
 
+pub fn kill(pid: libc::pid_t, signal: libc::c_int) -> io::Result<()> {
+    try_os!(unsafe { libc::kill(pid, signal) });
+    Ok(())
+}
+
+pub fn setpgid(pid: libc::pid_t, pgid: libc::pid_t) -> io::Result<()> {
+    try_os!(unsafe { libc::setpgid(pid, pgid) });
+    Ok(())
+}
+
 pub fn setsid() -> io::Result<libc::pid_t> {
     Ok(try_os!(unsafe { libc::setsid() }))
 }
@@ -78,5 +104,26 @@ pub fn fork() -> io::Result<Fork> {
 
 pub enum Fork {
     Child,
-    Parent(libc::pid_t),
+    Parent(#[allow(dead_code)] libc::pid_t),
+}
+
+pub fn flock(fd: RawFd, operation: libc::c_int) -> io::Result<()> {
+    try_os!(unsafe { libc::flock(fd, operation) });
+    Ok(())
+}
+
+#[cfg(test)]
+extern "C" {
+    fn tzset();
+}
+
+/// Makes local time functions use UTC, for tests that depend on the local
+/// timezone. Tests using local time should call this before doing so.
+#[cfg(test)]
+pub fn use_utc() {
+    static ONCE: std::sync::Once = std::sync::Once::new();
+    ONCE.call_once(|| {
+        std::env::set_var("TZ", "UTC0");
+        unsafe { tzset() };
+    });
 }
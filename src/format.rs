@@ -0,0 +1,114 @@
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Table,
+    Plain,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Format::Json),
+            "table" => Ok(Format::Table),
+            "plain" => Ok(Format::Plain),
+            _ => Err(anyhow!("format must be 'json', 'table' or 'plain'")),
+        }
+    }
+}
+
+/// Rows printed either tab separated or as aligned columns with a header.
+pub struct Table {
+    header: &'static [&'static str],
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn new(header: &'static [&'static str]) -> Self {
+        Self {
+            header,
+            rows: vec![],
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+
+    fn render(&self, format: Format) -> String {
+        let mut rows: Vec<Vec<&str>> = vec![];
+        if format == Format::Table && !self.header.is_empty() {
+            rows.push(self.header.to_vec());
+        }
+        rows.extend(
+            self.rows
+                .iter()
+                .map(|row| row.iter().map(|cell| cell.as_str()).collect()),
+        );
+        let mut widths = vec![];
+        for row in rows.iter() {
+            widths.resize(widths.len().max(row.len()), 0);
+            for (width, cell) in widths.iter_mut().zip(row.iter()) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        let mut output = String::new();
+        for row in rows {
+            let mut line = String::new();
+            for (index, cell) in row.iter().enumerate() {
+                if index > 0 {
+                    line.push_str(if format == Format::Table { "  " } else { "\t" });
+                }
+                line.push_str(cell);
+                if format == Format::Table && index + 1 < row.len() {
+                    let padding = widths[index] - cell.chars().count();
+                    line.extend(std::iter::repeat_n(' ', padding));
+                }
+            }
+            output.push_str(&line);
+            output.push('\n');
+        }
+        output
+    }
+}
+
+pub fn print_json<T>(value: &T) -> Result<()>
+where
+    T: Serialize,
+{
+    println!("{}", serde_json::to_string(value)?);
+    Ok(())
+}
+
+/// Prints `value` as JSON or the table built by `table`.
+pub fn print<T, F>(format: Format, value: &T, table: F) -> Result<()>
+where
+    T: Serialize,
+    F: FnOnce() -> Table,
+{
+    match format {
+        Format::Json => print_json(value),
+        Format::Table | Format::Plain => {
+            print!("{}", table().render(format));
+            Ok(())
+        }
+    }
+}
+
+#[test]
+fn test_render() {
+    let mut table = Table::new(&["ID", "COMMAND"]);
+    table.push(vec!["1".into(), "sleep 1".into()]);
+    table.push(vec!["100".into(), "true".into()]);
+    assert_eq!(table.render(Format::Plain), "1\tsleep 1\n100\ttrue\n");
+    assert_eq!(
+        table.render(Format::Table),
+        "ID   COMMAND\n1    sleep 1\n100  true\n"
+    );
+}
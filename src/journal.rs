@@ -0,0 +1,546 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use anyhow::{anyhow, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::ffi;
+use crate::request;
+use crate::response::TaskState;
+use crate::utils;
+use crate::worker::{Finished, Task};
+
+const JOURNAL_FILE: &str = "journal";
+const LOCK_FILE: &str = "lock";
+/// Minimal number of entries appended before the journal is compacted
+const COMPACT_THRESHOLD: usize = 1000;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    CreateQueue(request::CreateQueue),
+    RemoveQueue(request::RemoveQueue),
+    UpdateQueue(request::UpdateQueue),
+    PauseQueue(request::PauseQueue),
+    ResumeQueue(request::ResumeQueue),
+    /// The queue is defined in the configuration file
+    ConfigureQueue {
+        name: String,
+    },
+    Send {
+        queue: String,
+        task: Task,
+    },
+    AddSchedule {
+        id: u64,
+        schedule: request::AddSchedule,
+    },
+    RemoveSchedule {
+        id: u64,
+    },
+    Done {
+        id: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        state: Option<TaskState>,
+    },
+    Finished {
+        queue: String,
+        finished: Finished,
+    },
+    NextId {
+        id: u64,
+    },
+    NextScheduleId {
+        id: u64,
+    },
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: Mutex<JournalFile>,
+    _lock: File,
+}
+
+struct JournalFile {
+    file: File,
+    /// Number of entries in the file
+    entries: usize,
+    /// Number of entries at which the file is compacted
+    limit: usize,
+}
+
+impl JournalFile {
+    fn new(file: File, entries: usize) -> Self {
+        Self {
+            file,
+            entries,
+            limit: COMPACT_THRESHOLD.max(entries * 2),
+        }
+    }
+}
+
+impl Journal {
+    pub fn open<P>(dir: P) -> Result<(Self, Vec<Entry>)>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref();
+        // The journal holds the environment forwarded by clients
+        utils::create_private_dir(dir)?;
+
+        let lock = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .mode(0o600)
+            .open(dir.join(LOCK_FILE))?;
+        if let Err(err) = ffi::flock(lock.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                return Err(anyhow!(
+                    "state directory '{}' is used by another server",
+                    dir.display()
+                ));
+            }
+            return Err(err.into());
+        }
+
+        let path = dir.join(JOURNAL_FILE);
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .mode(0o600)
+            .open(&path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        let entries = read_entries(&file)?;
+        Ok((
+            Self {
+                path,
+                file: Mutex::new(JournalFile::new(file, entries.len())),
+                _lock: lock,
+            },
+            entries,
+        ))
+    }
+
+    pub fn append(&self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+        let mut file = self.file.lock().unwrap();
+        file.file.write_all(&line)?;
+        file.entries += 1;
+        if file.entries >= file.limit {
+            // The entry is already written so a failed compaction is not fatal
+            if let Err(err) = self.compact(&mut file) {
+                warn!("compacting journal failed: {}", err);
+                file.limit = file.entries * 2;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn rewrite(&self, entries: &[Entry]) -> io::Result<()> {
+        let mut file = self.file.lock().unwrap();
+        self.write(&mut file, entries)
+    }
+
+    /// Looks up the final state of a finished task.
+    pub fn outcome(&self, id: u64) -> io::Result<Option<TaskState>> {
+        // Keeps the file from being replaced while it is read
+        let _file = self.file.lock().unwrap();
+        let entries = read_entries(&File::open(&self.path)?)?;
+        Ok(outcomes(&entries).remove(&id))
+    }
+
+    /// Looks up the record of a finished task that is no longer in history.
+    pub fn finished(&self, id: u64) -> io::Result<Option<Finished>> {
+        let _file = self.file.lock().unwrap();
+        let entries = read_entries(&File::open(&self.path)?)?;
+        Ok(entries.into_iter().rev().find_map(|entry| match entry {
+            Entry::Finished { finished, .. } if finished.task.id == id => Some(finished),
+            _ => None,
+        }))
+    }
+
+    fn compact(&self, file: &mut JournalFile) -> io::Result<()> {
+        let entries = read_entries(&File::open(&self.path)?)?;
+        let next_id = next_id(&entries);
+        let next_schedule_id = next_schedule_id(&entries);
+        let outcomes = outcomes(&entries);
+        let entries = compact(&replay(entries), &outcomes, next_id, next_schedule_id);
+        self.write(file, &entries)
+    }
+
+    fn write(&self, file: &mut JournalFile, entries: &[Entry]) -> io::Result<()> {
+        let temp_path = self.path.with_extension("tmp");
+        let temp = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp_path)?;
+        let mut temp = io::BufWriter::new(temp);
+        for entry in entries {
+            serde_json::to_writer(&mut temp, entry)?;
+            temp.write_all(b"\n")?;
+        }
+        temp.into_inner()?.sync_all()?;
+        fs::rename(&temp_path, &self.path)?;
+        let reopened = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(&self.path)?;
+        *file = JournalFile::new(reopened, entries.len());
+        Ok(())
+    }
+}
+
+fn read_entries(file: &File) -> io::Result<Vec<Entry>> {
+    let mut entries = vec![];
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        match serde_json::from_str(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(err) => {
+                // A partially written entry can only be the last one
+                warn!("ignoring corrupted journal entry: {}", err);
+                break;
+            }
+        }
+    }
+    Ok(entries)
+}
+
+pub struct QueueState {
+    pub config: request::CreateQueue,
+    pub paused: bool,
+    /// Defined in the configuration file, retired once it is removed from
+    /// the file
+    pub configured: bool,
+    pub tasks: Vec<Task>,
+    pub schedules: Vec<(u64, request::AddSchedule)>,
+    pub history: Vec<Finished>,
+}
+
+pub fn replay(entries: Vec<Entry>) -> Vec<QueueState> {
+    let mut queues: Vec<QueueState> = vec![];
+    let mut done = HashSet::new();
+    for entry in entries.iter() {
+        match entry {
+            Entry::Done { id, .. } => {
+                done.insert(*id);
+            }
+            Entry::Finished { finished, .. } => {
+                done.insert(finished.task.id);
+            }
+            _ => {}
+        }
+    }
+    for entry in entries {
+        match entry {
+            Entry::CreateQueue(config) => {
+                queues.retain(|queue| queue.config.name != config.name);
+                queues.push(QueueState {
+                    config,
+                    paused: false,
+                    configured: false,
+                    tasks: vec![],
+                    schedules: vec![],
+                    history: vec![],
+                });
+            }
+            Entry::RemoveQueue(req) => queues.retain(|queue| queue.config.name != req.name),
+            Entry::UpdateQueue(req) => {
+                if let Some(state) = queues
+                    .iter_mut()
+                    .find(|state| state.config.name == req.name)
+                {
+                    state.config.update(&req);
+                }
+            }
+            Entry::PauseQueue(req) => {
+                if let Some(state) = queues
+                    .iter_mut()
+                    .find(|state| state.config.name == req.name)
+                {
+                    state.paused = true;
+                }
+            }
+            Entry::ResumeQueue(req) => {
+                if let Some(state) = queues
+                    .iter_mut()
+                    .find(|state| state.config.name == req.name)
+                {
+                    state.paused = false;
+                }
+            }
+            Entry::ConfigureQueue { name } => {
+                if let Some(state) = queues.iter_mut().find(|state| state.config.name == name) {
+                    state.configured = true;
+                }
+            }
+            Entry::Send { queue, task } => {
+                if done.contains(&task.id) {
+                    continue;
+                }
+                if let Some(state) = queues.iter_mut().find(|state| state.config.name == queue) {
+                    // Tasks are journaled again when they are retried
+                    match state.tasks.iter_mut().find(|queued| queued.id == task.id) {
+                        Some(queued) => *queued = task,
+                        None => state.tasks.push(task),
+                    }
+                }
+            }
+            Entry::AddSchedule { id, schedule } => {
+                if let Some(state) = queues
+                    .iter_mut()
+                    .find(|state| state.config.name == schedule.name)
+                {
+                    state.schedules.push((id, schedule));
+                }
+            }
+            Entry::RemoveSchedule { id } => {
+                for state in queues.iter_mut() {
+                    state
+                        .schedules
+                        .retain(|(schedule_id, _)| *schedule_id != id);
+                }
+            }
+            Entry::Finished { queue, finished } => {
+                if let Some(state) = queues.iter_mut().find(|state| state.config.name == queue) {
+                    state.history.push(finished);
+                    let excess = state
+                        .history
+                        .len()
+                        .saturating_sub(state.config.history_size);
+                    state.history.drain(..excess);
+                }
+            }
+            Entry::Done { .. } | Entry::NextId { .. } | Entry::NextScheduleId { .. } => {}
+        }
+    }
+    queues
+}
+
+pub fn next_id(entries: &[Entry]) -> u64 {
+    entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Entry::Send { task, .. } => Some(task.id + 1),
+            Entry::Done { id, .. } => Some(id + 1),
+            Entry::Finished { finished, .. } => Some(finished.task.id + 1),
+            Entry::NextId { id } => Some(*id),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(1)
+}
+
+pub fn next_schedule_id(entries: &[Entry]) -> u64 {
+    entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Entry::AddSchedule { id, .. } => Some(id + 1),
+            Entry::NextScheduleId { id } => Some(*id),
+            _ => None,
+        })
+        .max()
+        .unwrap_or(1)
+}
+
+/// Returns final states of finished tasks.
+pub fn outcomes(entries: &[Entry]) -> HashMap<u64, TaskState> {
+    entries
+        .iter()
+        .filter_map(|entry| match entry {
+            Entry::Done {
+                id,
+                state: Some(state),
+            } => Some((*id, *state)),
+            Entry::Finished { finished, .. } => Some((finished.task.id, finished.state)),
+            _ => None,
+        })
+        .collect()
+}
+
+pub fn compact(
+    queues: &[QueueState],
+    outcomes: &HashMap<u64, TaskState>,
+    next_id: u64,
+    next_schedule_id: u64,
+) -> Vec<Entry> {
+    let mut entries = vec![
+        Entry::NextId { id: next_id },
+        Entry::NextScheduleId {
+            id: next_schedule_id,
+        },
+    ];
+    // Outcomes are only needed for resolving dependencies of pending tasks
+    let mut dependencies: Vec<u64> = queues
+        .iter()
+        .flat_map(|queue| queue.tasks.iter())
+        .flat_map(|task| task.after.iter().map(|dependency| dependency.id))
+        .filter(|id| outcomes.contains_key(id))
+        .collect();
+    dependencies.sort_unstable();
+    dependencies.dedup();
+    entries.extend(dependencies.into_iter().map(|id| Entry::Done {
+        id,
+        state: outcomes.get(&id).cloned(),
+    }));
+    for queue in queues {
+        entries.push(Entry::CreateQueue(queue.config.clone()));
+        entries.extend(queue.history.iter().map(|finished| Entry::Finished {
+            queue: queue.config.name.clone(),
+            finished: finished.clone(),
+        }));
+        if queue.configured {
+            entries.push(Entry::ConfigureQueue {
+                name: queue.config.name.clone(),
+            });
+        }
+        if queue.paused {
+            entries.push(Entry::PauseQueue(request::PauseQueue {
+                name: queue.config.name.clone(),
+            }));
+        }
+        entries.extend(queue.tasks.iter().map(|task| Entry::Send {
+            queue: queue.config.name.clone(),
+            task: task.clone(),
+        }));
+        entries.extend(
+            queue
+                .schedules
+                .iter()
+                .map(|(id, schedule)| Entry::AddSchedule {
+                    id: *id,
+                    schedule: schedule.clone(),
+                }),
+        );
+    }
+    entries
+}
+
+#[test]
+fn test_replay() {
+    let create = |name: &str| {
+        Entry::CreateQueue(request::CreateQueue {
+            name: name.into(),
+            max_parallel: 1,
+            output: None,
+            timeout: None,
+            dir: None,
+            template: None,
+            retry: Default::default(),
+            kill: Default::default(),
+            env: Default::default(),
+            history_size: 100,
+        })
+    };
+    let task = |id| Task {
+        id,
+        binary: "true".into(),
+        timeout: None,
+        dir: None,
+        cwd: None,
+        args: vec![],
+        priority: 0,
+        at: None,
+        after: vec![],
+        stdin: Default::default(),
+        retry: Default::default(),
+        kill: Default::default(),
+        env: Default::default(),
+        attempts: 0,
+    };
+    let send = |queue: &str, id| Entry::Send {
+        queue: queue.into(),
+        task: task(id),
+    };
+    let finished = Entry::Finished {
+        queue: "a".into(),
+        finished: Finished {
+            task: task(5),
+            state: TaskState::Failed,
+            status: Some(std::os::unix::process::ExitStatusExt::from_raw(256)),
+            started: None,
+            ended: std::time::SystemTime::UNIX_EPOCH,
+        },
+    };
+    let finished: Entry = serde_json::from_str(&serde_json::to_string(&finished).unwrap()).unwrap();
+    let entries = vec![
+        create("a"),
+        create("b"),
+        send("a", 1),
+        send("b", 2),
+        send("a", 3),
+        Entry::Done { id: 1, state: None },
+        Entry::RemoveQueue(request::RemoveQueue { name: "b".into() }),
+        Entry::PauseQueue(request::PauseQueue { name: "a".into() }),
+        Entry::ConfigureQueue { name: "a".into() },
+        send("a", 4),
+        send("a", 5),
+        Entry::Send {
+            queue: "a".into(),
+            task: Task {
+                attempts: 1,
+                ..task(3)
+            },
+        },
+        finished,
+    ];
+    assert_eq!(outcomes(&entries).get(&5), Some(&TaskState::Failed));
+    let queues = replay(entries);
+    assert_eq!(queues.len(), 1);
+    assert_eq!(queues[0].config.name, "a");
+    assert!(queues[0].paused);
+    assert!(queues[0].configured);
+    assert_eq!(
+        queues[0]
+            .tasks
+            .iter()
+            .map(|task| task.id)
+            .collect::<Vec<_>>(),
+        vec![3, 4]
+    );
+    assert_eq!(queues[0].tasks[0].attempts, 1);
+    assert_eq!(queues[0].history.len(), 1);
+    assert_eq!(
+        queues[0].history[0].status.and_then(|status| status.code()),
+        Some(1)
+    );
+}
+
+#[test]
+fn test_compact_on_append() {
+    let dir = std::env::temp_dir().join(format!("pqueue-journal-{}", std::process::id()));
+    let (journal, _) = Journal::open(&dir).unwrap();
+    journal
+        .append(&Entry::CreateQueue(request::CreateQueue {
+            name: "a".into(),
+            max_parallel: 1,
+            output: None,
+            timeout: None,
+            dir: None,
+            template: None,
+            retry: Default::default(),
+            kill: Default::default(),
+            env: Default::default(),
+            history_size: 100,
+        }))
+        .unwrap();
+    for id in 1..=COMPACT_THRESHOLD as u64 {
+        journal.append(&Entry::NextId { id }).unwrap();
+    }
+    let entries = read_entries(&File::open(dir.join(JOURNAL_FILE)).unwrap()).unwrap();
+    fs::remove_dir_all(&dir).unwrap();
+    assert!(entries.len() < COMPACT_THRESHOLD);
+    assert_eq!(next_id(&entries), COMPACT_THRESHOLD as u64);
+    assert_eq!(replay(entries).len(), 1);
+}
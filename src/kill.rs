@@ -0,0 +1,135 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error, Result};
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+
+use crate::seconds;
+
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+];
+
+const DEFAULT_GRACE: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Signal(libc::c_int);
+
+impl Signal {
+    pub const TERM: Signal = Signal(libc::SIGTERM);
+    pub const KILL: Signal = Signal(libc::SIGKILL);
+
+    pub fn number(self) -> libc::c_int {
+        self.0
+    }
+}
+
+impl FromStr for Signal {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Ok(number) = s.parse() {
+            if number > 0 && number < 32 {
+                return Ok(Signal(number));
+            }
+        }
+        let upper = s.to_ascii_uppercase();
+        let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+        SIGNALS
+            .iter()
+            .find(|(known, _)| *known == name)
+            .map(|(_, number)| Signal(*number))
+            .ok_or_else(|| anyhow!("unknown signal '{}'", s))
+    }
+}
+
+impl fmt::Display for Signal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match SIGNALS.iter().find(|(_, number)| *number == self.0) {
+            Some((name, _)) => write!(f, "{}", name),
+            None => write!(f, "{}", self.0),
+        }
+    }
+}
+
+impl Serialize for Signal {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Signal {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// How a task is stopped: `signal` is sent first and the task is killed
+/// with SIGKILL if it is still running after `grace`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct KillPolicy {
+    pub signal: Option<Signal>,
+    #[serde(with = "seconds::option")]
+    pub grace: Option<Duration>,
+}
+
+impl KillPolicy {
+    pub fn or(self, defaults: &KillPolicy) -> KillPolicy {
+        KillPolicy {
+            signal: self.signal.or(defaults.signal),
+            grace: self.grace.or(defaults.grace),
+        }
+    }
+
+    pub fn signal(&self) -> Signal {
+        self.signal.unwrap_or(Signal::TERM)
+    }
+
+    pub fn grace(&self) -> Duration {
+        self.grace.unwrap_or(DEFAULT_GRACE)
+    }
+}
+
+#[test]
+fn test_kill_policy() {
+    assert_eq!("TERM".parse::<Signal>().unwrap(), Signal::TERM);
+    assert_eq!("sigkill".parse::<Signal>().unwrap(), Signal::KILL);
+    assert_eq!("2".parse::<Signal>().unwrap(), Signal(libc::SIGINT));
+    assert_eq!(Signal(libc::SIGINT).to_string(), "INT");
+    assert!("FOO".parse::<Signal>().is_err());
+    assert!("0".parse::<Signal>().is_err());
+
+    let policy = KillPolicy::default();
+    assert_eq!(policy.signal(), Signal::TERM);
+    assert_eq!(policy.grace(), DEFAULT_GRACE);
+
+    let merged = KillPolicy {
+        signal: Some(Signal(libc::SIGINT)),
+        grace: None,
+    }
+    .or(&KillPolicy {
+        signal: Some(Signal::KILL),
+        grace: Some(Duration::from_secs(1)),
+    });
+    assert_eq!(merged.signal(), Signal(libc::SIGINT));
+    assert_eq!(merged.grace(), Duration::from_secs(1));
+
+    let json = serde_json::to_string(&merged).unwrap();
+    assert_eq!(serde_json::from_str::<KillPolicy>(&json).unwrap(), merged);
+}
@@ -1,19 +1,30 @@
 mod args;
 mod client;
 mod command;
+mod config;
 mod connection;
+mod cron;
 mod daemon;
 mod duration;
+mod env;
 mod fd;
 mod ffi;
+mod format;
+mod journal;
+mod kill;
 mod output;
 mod process;
 mod queue;
 mod request;
 mod response;
+mod retry;
+mod scheduler;
+mod seconds;
 mod server;
+mod spool;
 mod sync;
 mod template;
+mod time;
 mod utils;
 mod worker;
 
@@ -32,12 +43,24 @@ fn run() -> Result<()> {
 
     rt.block_on(async {
         match args.command {
-            Command::StopServer => command::stop_server(args.global).await,
+            Command::StopServer(stop) => command::stop_server(args.global, stop).await,
             Command::CreateQueue(create) => command::create_queue(args.global, create).await,
             Command::RemoveQueue(remove) => command::remove_queue(args.global, remove).await,
+            Command::UpdateQueue(update) => command::update_queue(args.global, update).await,
             Command::SendTask(send) => command::send(args.global, send).await,
             Command::ListQueues => command::list_queues(args.global).await,
+            Command::ShowQueue(show) => command::show_queue(args.global, show).await,
+            Command::CheckConfig(check) => command::check_config(args.global, check).await,
+            Command::Reload => command::reload(args.global).await,
             Command::ListTasks(list_tasks) => command::list_tasks(args.global, list_tasks).await,
+            Command::CancelTask(cancel) => command::cancel_task(args.global, cancel).await,
+            Command::ListHistory(history) => command::history(args.global, history).await,
+            Command::Wait(wait) => command::wait(args.global, wait).await,
+            Command::Logs(logs) => command::logs(args.global, logs).await,
+            Command::Attach(attach) => command::attach(args.global, attach).await,
+            Command::Schedule(schedule) => command::schedule(args.global, schedule).await,
+            Command::PauseQueue(pause) => command::pause_queue(args.global, pause).await,
+            Command::ResumeQueue(resume) => command::resume_queue(args.global, resume).await,
             Command::StartServer(..) => unreachable!(),
         }
     })
@@ -4,12 +4,13 @@ use std::io::{self, Write};
 use std::path::Path;
 
 use anyhow::{bail, Result};
-use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::io::{AsyncBufReadExt, BufReader};
 use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
 use tokio::sync::oneshot;
 
 use crate::fd::{AsyncFd, Fd};
 use crate::ffi::{pipe, Pipe};
+use crate::spool::CaptureWriter;
 
 pub struct Output {
     line_sender: UnboundedSender<String>,
@@ -36,23 +37,46 @@ impl Source {
 struct Forwarder {
     name: String,
     line_sender: UnboundedSender<String>,
-    reader: Lines<BufReader<AsyncFd>>,
+    reader: BufReader<AsyncFd>,
+    spool: Option<CaptureWriter>,
 }
 
 impl Forwarder {
-    fn new(name: String, reader: AsyncFd, line_sender: UnboundedSender<String>) -> Self {
-        let reader = BufReader::new(reader).lines();
+    fn new(
+        name: String,
+        reader: AsyncFd,
+        line_sender: UnboundedSender<String>,
+        spool: Option<CaptureWriter>,
+    ) -> Self {
+        let reader = BufReader::new(reader);
         Self {
             name,
             line_sender,
             reader,
+            spool,
         }
     }
 
     async fn serve(&mut self) -> io::Result<()> {
         debug!("output forwarder '{}' started", self.name);
-        while let Some(line) = self.reader.next_line().await? {
-            let line = format!("{}{}", self.name, line);
+        let mut buffer = vec![];
+        loop {
+            buffer.clear();
+            if self.reader.read_until(b'\n', &mut buffer).await? == 0 {
+                break;
+            }
+            if buffer.last() == Some(&b'\n') {
+                buffer.pop();
+            }
+            // Output is spooled as is, invalid UTF-8 is only replaced when
+            // forwarding lines
+            if let Some(spool) = &mut self.spool {
+                if let Err(err) = spool.write_line(&buffer).await {
+                    error!("error writing output of '{}': {}", self.name, err);
+                    self.spool = None;
+                }
+            }
+            let line = format!("{}{}", self.name, String::from_utf8_lossy(&buffer));
             if self.line_sender.send(line).is_err() {
                 debug!("error forwarding output from '{}'", self.name);
                 break;
@@ -114,7 +138,9 @@ impl Output {
         Self { line_sender }
     }
 
-    pub fn add_source(&self) -> io::Result<Source> {
+    /// Adds a new source of lines, the lines are also written to `spool`
+    /// without the prefix.
+    pub fn add_source(&self, spool: Option<CaptureWriter>) -> io::Result<Source> {
         let Pipe { reader, writer } = pipe()?;
         let reader = Fd::new(reader);
         let writer = Fd::new(writer);
@@ -129,7 +155,7 @@ impl Output {
                     return;
                 }
             };
-            let mut forwarder = Forwarder::new(name, reader, line_sender);
+            let mut forwarder = Forwarder::new(name, reader, line_sender, spool);
             if let Err(err) = forwarder.serve().await {
                 error!("output forwarding error: {}", err);
             }
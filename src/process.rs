@@ -2,21 +2,55 @@ use std::collections::HashSet;
 use std::io;
 use std::os::unix::io::FromRawFd;
 use std::os::unix::io::RawFd;
-use std::process::Stdio;
+use std::process::{ExitStatus, Stdio};
 use std::string::ToString;
 
 use anyhow::Result;
 use log::{error, info, warn};
 use tokio::process::{Child, Command};
 
+use crate::duration::format_duration;
 use crate::ffi;
+use crate::kill::Signal;
 use crate::output::Source;
+use crate::response::TaskState;
 use crate::sync::Waiter;
 use crate::worker::Task;
 
+pub enum Outcome {
+    Exited(ExitStatus),
+    Failed,
+    TimedOut(Option<ExitStatus>),
+    Cancelled(Option<ExitStatus>),
+    Interrupted,
+}
+
+impl Outcome {
+    pub fn state(&self) -> Option<TaskState> {
+        match self {
+            Outcome::Exited(status) if status.success() => Some(TaskState::Finished),
+            Outcome::Exited(_) | Outcome::Failed => Some(TaskState::Failed),
+            Outcome::TimedOut(_) => Some(TaskState::TimedOut),
+            Outcome::Cancelled(_) => Some(TaskState::Cancelled),
+            Outcome::Interrupted => None,
+        }
+    }
+
+    pub fn status(&self) -> Option<ExitStatus> {
+        match self {
+            Outcome::Exited(status) => Some(*status),
+            Outcome::TimedOut(status) | Outcome::Cancelled(status) => *status,
+            Outcome::Failed | Outcome::Interrupted => None,
+        }
+    }
+}
+
 pub struct Process {
     child: Child,
+    // Also the id of the process group of the task
+    pid: libc::pid_t,
     task: Task,
+    cancel: Waiter,
     worker_shutdown: Waiter,
     server_shutdown: Waiter,
 }
@@ -37,12 +71,9 @@ fn close_fds(keep: HashSet<RawFd>) -> io::Result<()> {
                 .map(|path| path.file_name())
                 .and_then(|name| {
                     name.into_string()
-                        .map_err(|_| io::Error::new(io::ErrorKind::Other, "invalid fd"))
-                })
-                .and_then(|name| {
-                    name.parse()
-                        .map_err(|_| io::Error::new(io::ErrorKind::Other, "invalid fd"))
+                        .map_err(|_| io::Error::other("invalid fd"))
                 })
+                .and_then(|name| name.parse().map_err(|_| io::Error::other("invalid fd")))
         })
         .collect();
     for fd in open_fds?.into_iter() {
@@ -56,61 +87,121 @@ fn close_fds(keep: HashSet<RawFd>) -> io::Result<()> {
 impl Process {
     pub fn new(
         task: Task,
+        stdin: Stdio,
         mut stdout: Source,
         mut stderr: Source,
+        cancel: Waiter,
         worker_shutdown: Waiter,
         server_shutdown: Waiter,
     ) -> Result<Self> {
         let mut command = Command::new(&task.binary);
-        command.stdin(Stdio::null());
+        command.stdin(stdin);
         command.stdout(unsafe { Stdio::from_raw_fd(stdout.take_writer().unwrap().into()) });
         command.stderr(unsafe { Stdio::from_raw_fd(stderr.take_writer().unwrap().into()) });
         unsafe {
-            command.pre_exec(|| close_fds([0, 1, 2].iter().cloned().collect()));
+            command.pre_exec(|| {
+                // Signals are sent to the whole process group of the task
+                ffi::setpgid(0, 0)?;
+                close_fds([0, 1, 2].iter().cloned().collect())
+            });
         };
         command.args(&task.args);
+        task.env.apply(&mut command);
         if let Some(ref dir) = task.dir {
             command.current_dir(dir);
         }
         let child = command.spawn()?;
         // We have never polled child so id() should never return None
-        let pid = child.id().map(|pid| pid.to_string()).unwrap();
+        let pid = child.id().unwrap();
         stdout.set_prefix(format!("[{}:stdout]: ", pid)).unwrap();
         stderr.set_prefix(format!("[{}:stderr]: ", pid)).unwrap();
         Ok(Self {
             child,
+            pid: pid as libc::pid_t,
             task,
+            cancel,
             worker_shutdown,
             server_shutdown,
         })
     }
 
-    pub async fn wait(&mut self) {
+    pub fn id(&self) -> Option<u32> {
+        self.child.id()
+    }
+
+    pub async fn wait(&mut self) -> Outcome {
         if let Some(duration) = self.task.timeout {
-            if tokio::time::timeout(duration, self.wait_inner())
-                .await
-                .is_err()
-            {
-                warn!("execution of '{}' timed out", self.task.to_string());
-                let _ = self.child.start_kill();
+            match tokio::time::timeout(duration, self.wait_inner()).await {
+                Ok(outcome) => outcome,
+                Err(_) => {
+                    warn!("execution of '{}' timed out", self.task.to_string());
+                    Outcome::TimedOut(self.kill().await)
+                }
             }
         } else {
             self.wait_inner().await
         }
     }
 
-    async fn wait_inner(&mut self) {
+    async fn wait_inner(&mut self) -> Outcome {
         tokio::select! {
             ret = self.child.wait() => match ret {
-                Ok(ret) => info!("execution of '{}' finished: {}", self.task.to_string(), ret),
-                Err(err) => error!("error executing '{}': {}", self.task.to_string(), err),
+                Ok(ret) => {
+                    info!("execution of '{}' finished: {}", self.task.to_string(), ret);
+                    Outcome::Exited(ret)
+                }
+                Err(err) => {
+                    error!("error executing '{}': {}", self.task.to_string(), err);
+                    Outcome::Failed
+                }
+            },
+            _ = self.cancel.wait() => {
+                info!("execution of '{}' cancelled", self.task.to_string());
+                Outcome::Cancelled(self.kill().await)
             },
             _ = self.worker_shutdown.wait() => {
-                let _ = self.child.start_kill();
+                // The queue was removed, the task will not be executed again
+                // so it is reported as cancelled to anyone waiting for it
+                Outcome::Cancelled(self.kill().await)
             },
             _ = self.server_shutdown.wait() => {
-                let _ = self.child.start_kill();
+                self.kill().await;
+                Outcome::Interrupted
             },
         }
     }
+
+    fn signal_group(&self, signal: Signal) -> io::Result<()> {
+        ffi::kill(-self.pid, signal.number())
+    }
+
+    /// Sends the stop signal to the process group of the task and kills the
+    /// group if the task does not exit within the grace period.
+    async fn kill(&mut self) -> Option<ExitStatus> {
+        let signal = self.task.kill.signal();
+        if signal != Signal::KILL {
+            match self.signal_group(signal) {
+                Ok(()) => {
+                    let grace = self.task.kill.grace();
+                    match tokio::time::timeout(grace, self.child.wait()).await {
+                        Ok(ret) => {
+                            // Remaining processes of the group are not waited for
+                            let _ = self.signal_group(Signal::KILL);
+                            return ret.ok();
+                        }
+                        Err(_) => warn!(
+                            "'{}' did not stop within {}, killing it",
+                            self.task.to_string(),
+                            format_duration(grace)
+                        ),
+                    }
+                }
+                Err(err) => warn!("error sending SIG{} to {}: {}", signal, self.pid, err),
+            }
+        }
+        if self.signal_group(Signal::KILL).is_err() {
+            let _ = self.child.start_kill();
+        }
+        self.child.wait().await.ok()
+    }
 }
@@ -2,12 +2,16 @@ use std::collections::VecDeque;
 use std::iter::FromIterator;
 use tokio::sync::{Mutex, Semaphore};
 
+pub trait Priority {
+    fn priority(&self) -> i32;
+}
+
 pub struct Queue<T> {
     queue: Mutex<VecDeque<T>>,
     semaphore: Semaphore,
 }
 
-impl<T> Queue<T> {
+impl<T: Priority> Queue<T> {
     pub fn new() -> Self {
         Queue {
             queue: Mutex::new(VecDeque::new()),
@@ -16,13 +20,73 @@ impl<T> Queue<T> {
     }
 
     pub async fn push(&self, task: T) {
-        self.queue.lock().await.push_back(task);
+        let mut queue = self.queue.lock().await;
+        Self::insert(&mut queue, task);
         self.semaphore.add_permits(1);
     }
 
-    pub async fn pop(&self) -> T {
-        self.semaphore.acquire().await.unwrap().forget();
-        self.queue.lock().await.pop_front().expect("empty queue")
+    /// Removes the first item and passes it to `f` while the queue is still
+    /// locked, so that the item can be moved elsewhere without it being
+    /// momentarily absent from both places.
+    pub async fn pop_with<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(T) -> R,
+    {
+        loop {
+            let permit = self.semaphore.acquire().await.unwrap();
+            let mut queue = self.queue.lock().await;
+            let task = queue.pop_front();
+            permit.forget();
+            // The item might have been removed after we acquired the permit
+            if let Some(task) = task {
+                return f(task);
+            }
+        }
+    }
+
+    /// Like `push` but the item is produced by `f` while the queue is locked.
+    pub async fn push_with<F>(&self, f: F)
+    where
+        F: FnOnce() -> Option<T>,
+    {
+        let mut queue = self.queue.lock().await;
+        if let Some(task) = f() {
+            Self::insert(&mut queue, task);
+            self.semaphore.add_permits(1);
+        }
+    }
+
+    fn insert(queue: &mut VecDeque<T>, task: T) {
+        // Items with equal priority are kept in FIFO order
+        let index = queue
+            .iter()
+            .position(|item| item.priority() < task.priority())
+            .unwrap_or(queue.len());
+        queue.insert(index, task);
+    }
+
+    /// Calls `f` with the queued items while the queue is locked.
+    pub async fn inspect<F, R>(&self, f: F) -> R
+    where
+        F: FnOnce(&VecDeque<T>) -> R,
+    {
+        f(&*self.queue.lock().await)
+    }
+
+    pub async fn len(&self) -> usize {
+        self.queue.lock().await.len()
+    }
+
+    pub async fn remove<F>(&self, pred: F) -> Option<T>
+    where
+        F: FnMut(&T) -> bool,
+    {
+        let mut queue = self.queue.lock().await;
+        let index = queue.iter().position(pred)?;
+        if let Ok(permit) = self.semaphore.try_acquire() {
+            permit.forget();
+        }
+        queue.remove(index)
     }
 }
 
@@ -34,3 +98,34 @@ impl<T: Clone> Queue<T> {
         self.queue.lock().await.iter().cloned().collect()
     }
 }
+
+#[cfg(test)]
+impl Priority for (i32, &str) {
+    fn priority(&self) -> i32 {
+        self.0
+    }
+}
+
+#[tokio::test]
+async fn test_priority() {
+    let queue = Queue::new();
+    queue.push((0, "a")).await;
+    queue.push((0, "b")).await;
+    queue.push((1, "c")).await;
+    queue.push((-1, "d")).await;
+    queue.push((1, "e")).await;
+    queue.push((0, "f")).await;
+    assert_eq!(
+        queue
+            .collect::<Vec<_>>()
+            .await
+            .into_iter()
+            .map(|(_, name)| name)
+            .collect::<Vec<_>>(),
+        vec!["c", "e", "a", "b", "f", "d"]
+    );
+    assert_eq!(queue.remove(|(_, name)| *name == "a").await, Some((0, "a")));
+    assert_eq!(queue.pop_with(|item| item).await, (1, "c"));
+    assert_eq!(queue.pop_with(|item| item).await, (1, "e"));
+    assert_eq!(queue.pop_with(|item| item).await, (0, "b"));
+}
@@ -1,11 +1,28 @@
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use std::path::PathBuf;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
+use crate::cron::Recurrence;
+use crate::env::Environment;
 use crate::impl_trivial_from;
+use crate::kill::KillPolicy;
+use crate::retry::RetryPolicy;
 use crate::template::Template;
 
-#[derive(Debug, Serialize, Deserialize)]
+/// With `drain` the server stops accepting tasks and waits for the running
+/// tasks, and the queued ones if `queued` is set, to finish before stopping.
+/// Tasks still running after `timeout` are stopped.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct StopServer {
+    #[serde(default)]
+    pub drain: bool,
+    #[serde(default)]
+    pub queued: bool,
+    #[serde(default)]
+    pub timeout: Option<Duration>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct CreateQueue {
     pub name: String,
     pub max_parallel: usize,
@@ -13,37 +30,358 @@ pub struct CreateQueue {
     pub timeout: Option<Duration>,
     pub dir: Option<PathBuf>,
     pub template: Option<Template>,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub kill: KillPolicy,
+    #[serde(default)]
+    pub env: Environment,
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+pub const DEFAULT_HISTORY_SIZE: usize = 100;
+
+fn default_history_size() -> usize {
+    DEFAULT_HISTORY_SIZE
+}
+
+impl CreateQueue {
+    pub fn update(&mut self, update: &UpdateQueue) {
+        if let Some(max_parallel) = update.max_parallel {
+            self.max_parallel = max_parallel;
+        }
+        if let Some(output) = &update.output {
+            self.output = output.clone();
+        }
+        if let Some(timeout) = update.timeout {
+            self.timeout = timeout;
+        }
+        if let Some(dir) = &update.dir {
+            self.dir = dir.clone();
+        }
+        if let Some(template) = &update.template {
+            self.template = template.clone();
+        }
+        if let Some(retry) = &update.retry {
+            self.retry = retry.clone();
+        }
+        if let Some(kill) = update.kill {
+            self.kill = kill;
+        }
+        if let Some(env) = &update.env {
+            self.env = env.clone();
+        }
+        if let Some(history_size) = update.history_size {
+            self.history_size = history_size;
+        }
+    }
+
+    /// Returns the update that turns these settings into `other`.
+    pub fn changes(&self, other: &CreateQueue) -> UpdateQueue {
+        fn changed<T: PartialEq + Clone>(current: &T, new: &T) -> Option<T> {
+            if current != new {
+                Some(new.clone())
+            } else {
+                None
+            }
+        }
+        UpdateQueue {
+            name: other.name.clone(),
+            max_parallel: changed(&self.max_parallel, &other.max_parallel),
+            output: changed(&self.output, &other.output),
+            timeout: changed(&self.timeout, &other.timeout),
+            dir: changed(&self.dir, &other.dir),
+            template: changed(&self.template, &other.template),
+            retry: changed(&self.retry, &other.retry),
+            kill: changed(&self.kill, &other.kill),
+            env: changed(&self.env, &other.env),
+            history_size: changed(&self.history_size, &other.history_size),
+        }
+    }
+}
+
+/// Changes to the queue settings, `Some(None)` resets the setting.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UpdateQueue {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_parallel: Option<usize>,
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub output: Option<Option<PathBuf>>,
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub timeout: Option<Option<Duration>>,
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub dir: Option<Option<PathBuf>>,
+    #[serde(
+        default,
+        deserialize_with = "double_option",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub template: Option<Option<Template>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry: Option<RetryPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kill: Option<KillPolicy>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<Environment>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub history_size: Option<usize>,
+}
+
+impl UpdateQueue {
+    pub fn is_empty(&self) -> bool {
+        self.max_parallel.is_none()
+            && self.output.is_none()
+            && self.timeout.is_none()
+            && self.dir.is_none()
+            && self.template.is_none()
+            && self.retry.is_none()
+            && self.kill.is_none()
+            && self.env.is_none()
+            && self.history_size.is_none()
+    }
+}
+
+// Distinguishes a missing field from an explicit null
+fn double_option<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RemoveQueue {
     pub name: String,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShowQueue {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PauseQueue {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResumeQueue {
+    pub name: String,
+}
+
+/// Task that has to finish before the dependent task is started. Unless
+/// `any` is set, the task also has to succeed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Dependency {
+    pub id: u64,
+    #[serde(default)]
+    pub any: bool,
+}
+
+/// Source of the standard input of a task. With `Spool` the input follows
+/// the request as length-prefixed chunks ending with an empty chunk, see
+/// `Connection::write_raw`, and the connection stays usable afterwards.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stdin {
+    #[default]
+    Null,
+    Spool,
+    File(PathBuf),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Send {
     pub name: String,
     pub timeout: Option<Duration>,
     pub dir: Option<PathBuf>,
+    /// Working directory of the client, used if neither the task nor the
+    /// queue sets one.
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
     pub args: Vec<String>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub at: Option<SystemTime>,
+    #[serde(default)]
+    pub after: Vec<Dependency>,
+    #[serde(default)]
+    pub stdin: Stdin,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub kill: KillPolicy,
+    #[serde(default)]
+    pub env: Environment,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AddSchedule {
+    pub name: String,
+    pub when: Recurrence,
+    pub skip_if_running: bool,
+    pub timeout: Option<Duration>,
+    pub dir: Option<PathBuf>,
+    /// Working directory of the client, used if neither the task nor the
+    /// queue sets one.
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub kill: KillPolicy,
+    #[serde(default)]
+    pub env: Environment,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ListSchedules {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoveSchedule {
+    pub id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ListTasks {
     pub name: String,
 }
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct History {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WaitTask {
+    pub id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WaitQueue {
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CancelTask {
+    pub id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Logs {
+    pub id: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FollowLogs {
+    pub id: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum Request {
-    StopServer,
+    StopServer(StopServer),
     CreateQueue(CreateQueue),
     RemoveQueue(RemoveQueue),
+    UpdateQueue(UpdateQueue),
     Send(Send),
     ListQueues,
+    ShowQueue(ShowQueue),
     ListTasks(ListTasks),
+    CancelTask(CancelTask),
+    History(History),
+    WaitTask(WaitTask),
+    WaitQueue(WaitQueue),
+    PauseQueue(PauseQueue),
+    ResumeQueue(ResumeQueue),
+    Logs(Logs),
+    FollowLogs(FollowLogs),
+    AddSchedule(AddSchedule),
+    ListSchedules(ListSchedules),
+    RemoveSchedule(RemoveSchedule),
+    Reload,
 }
 
+impl_trivial_from!(StopServer, Request, StopServer);
 impl_trivial_from!(CreateQueue, Request, CreateQueue);
 impl_trivial_from!(RemoveQueue, Request, RemoveQueue);
+impl_trivial_from!(UpdateQueue, Request, UpdateQueue);
 impl_trivial_from!(Send, Request, Send);
+impl_trivial_from!(ShowQueue, Request, ShowQueue);
 impl_trivial_from!(ListTasks, Request, ListTasks);
+impl_trivial_from!(CancelTask, Request, CancelTask);
+impl_trivial_from!(History, Request, History);
+impl_trivial_from!(WaitTask, Request, WaitTask);
+impl_trivial_from!(WaitQueue, Request, WaitQueue);
+impl_trivial_from!(PauseQueue, Request, PauseQueue);
+impl_trivial_from!(ResumeQueue, Request, ResumeQueue);
+impl_trivial_from!(Logs, Request, Logs);
+impl_trivial_from!(FollowLogs, Request, FollowLogs);
+impl_trivial_from!(AddSchedule, Request, AddSchedule);
+impl_trivial_from!(ListSchedules, Request, ListSchedules);
+impl_trivial_from!(RemoveSchedule, Request, RemoveSchedule);
+
+#[test]
+fn test_update_queue() {
+    let update: UpdateQueue =
+        serde_json::from_str(r#"{"name": "a", "max_parallel": 2, "timeout": null}"#).unwrap();
+    assert_eq!(update.max_parallel, Some(2));
+    assert_eq!(update.timeout, Some(None));
+    assert_eq!(update.dir, None);
+    let json = serde_json::to_string(&update).unwrap();
+    assert_eq!(json, r#"{"name":"a","max_parallel":2,"timeout":null}"#);
+
+    let current = CreateQueue {
+        name: "a".into(),
+        max_parallel: 1,
+        output: None,
+        timeout: Some(Duration::from_secs(60)),
+        dir: None,
+        template: None,
+        retry: RetryPolicy::default(),
+        kill: KillPolicy::default(),
+        env: Environment::default(),
+        history_size: DEFAULT_HISTORY_SIZE,
+    };
+    assert!(current.changes(&current).is_empty());
+    let mut new = current.clone();
+    new.timeout = None;
+    new.history_size = 10;
+    let changes = current.changes(&new);
+    assert_eq!(changes.timeout, Some(None));
+    assert_eq!(changes.history_size, Some(10));
+    assert_eq!(changes.max_parallel, None);
+    let mut updated = current;
+    updated.update(&changes);
+    assert_eq!(updated, new);
+}
+
+#[test]
+fn test_stop_server() {
+    let req: Request = serde_json::from_str(r#"{"type": "stop_server"}"#).unwrap();
+    match req {
+        Request::StopServer(stop) => assert!(!stop.drain && stop.timeout.is_none()),
+        _ => panic!("unexpected request"),
+    }
+}
@@ -1,5 +1,12 @@
+use crate::env::Environment;
 use crate::impl_trivial_from;
+use crate::kill::KillPolicy;
+use crate::retry::RetryPolicy;
+use crate::seconds;
 use anyhow::anyhow;
+use std::fmt;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
 
 use serde::{Deserialize, Serialize};
 
@@ -10,9 +17,49 @@ pub fn ok<E>() -> Result<Empty, E> {
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Empty {}
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Send {
+    pub id: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AddSchedule {
+    pub id: u64,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Queue {
     pub name: String,
+    pub paused: bool,
+    /// Removed from the configuration file, the queue is removed once its
+    /// tasks have finished
+    pub retiring: bool,
+    pub max_parallel: usize,
+    pub output: Option<PathBuf>,
+    #[serde(with = "seconds::option")]
+    pub timeout: Option<Duration>,
+    pub dir: Option<PathBuf>,
+    pub template: Option<String>,
+    pub retry: RetryPolicy,
+    pub kill: KillPolicy,
+    pub env: Environment,
+    pub history_size: usize,
+    pub stats: QueueStats,
+}
+
+/// Counters of a queue since it was created or restored.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct QueueStats {
+    /// Tasks that are queued, scheduled or blocked
+    pub queued: usize,
+    pub running: usize,
+    pub completed: u64,
+    /// Tasks that failed or timed out
+    pub failed: u64,
+    #[serde(with = "seconds::option")]
+    pub average_runtime: Option<Duration>,
+    #[serde(with = "seconds")]
+    pub uptime: Duration,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -20,9 +67,57 @@ pub struct ListQueues {
     pub queues: Vec<Queue>,
 }
 
+/// Names of the queues changed by reloading the configuration file.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Reload {
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub retired: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskState {
+    Blocked,
+    Queued,
+    Scheduled,
+    Running,
+    Finished,
+    Failed,
+    TimedOut,
+    Cancelled,
+}
+
+impl fmt::Display for TaskState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TaskState::Blocked => "blocked",
+            TaskState::Queued => "queued",
+            TaskState::Scheduled => "scheduled",
+            TaskState::Running => "running",
+            TaskState::Finished => "finished",
+            TaskState::Failed => "failed",
+            TaskState::TimedOut => "timed_out",
+            TaskState::Cancelled => "cancelled",
+        };
+        write!(f, "{}", name)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Task {
+    pub id: u64,
     pub args: Vec<String>,
+    pub state: TaskState,
+    pub priority: i32,
+    pub attempt: u32,
+    pub pid: Option<u32>,
+    #[serde(with = "seconds::option")]
+    pub scheduled: Option<SystemTime>,
+    #[serde(with = "seconds::option")]
+    pub started: Option<SystemTime>,
+    #[serde(with = "seconds::option")]
+    pub elapsed: Option<Duration>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,22 +125,108 @@ pub struct ListTasks {
     pub tasks: Vec<Task>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct FinishedTask {
+    pub id: u64,
+    pub args: Vec<String>,
+    pub state: TaskState,
+    pub attempts: u32,
+    pub exit_code: Option<i32>,
+    pub signal: Option<i32>,
+    #[serde(with = "seconds::option")]
+    pub started: Option<SystemTime>,
+    #[serde(with = "seconds")]
+    pub ended: SystemTime,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct History {
+    pub tasks: Vec<FinishedTask>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Wait {
+    pub tasks: Vec<FinishedTask>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Schedule {
+    pub id: u64,
+    pub when: String,
+    pub args: Vec<String>,
+    pub skip_if_running: bool,
+    #[serde(with = "seconds::option")]
+    pub next: Option<SystemTime>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ListSchedules {
+    pub schedules: Vec<Schedule>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Streamed in response to `FollowLogs` until `End` is sent.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Output {
+    Data { stream: Stream, data: String },
+    End,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 #[serde(untagged)]
+#[allow(clippy::large_enum_variant)]
 pub enum Any {
     Empty(Empty),
+    Send(Send),
+    AddSchedule(AddSchedule),
+    Queue(Queue),
     ListQueues(ListQueues),
     ListTasks(ListTasks),
+    History(History),
+    Wait(Wait),
+    ListSchedules(ListSchedules),
+    Reload(Reload),
 }
 
 pub trait ToAny: Into<Any> {}
 impl ToAny for Empty {}
+impl ToAny for Send {}
+impl ToAny for AddSchedule {}
+impl ToAny for Queue {}
 impl ToAny for ListQueues {}
 impl ToAny for ListTasks {}
+impl ToAny for History {}
+impl ToAny for Wait {}
+impl ToAny for ListSchedules {}
+impl ToAny for Reload {}
 
 impl_trivial_from!(Empty, Any, Empty);
+impl_trivial_from!(Send, Any, Send);
+impl_trivial_from!(AddSchedule, Any, AddSchedule);
+impl_trivial_from!(Queue, Any, Queue);
 impl_trivial_from!(ListQueues, Any, ListQueues);
 impl_trivial_from!(ListTasks, Any, ListTasks);
+impl_trivial_from!(History, Any, History);
+impl_trivial_from!(Wait, Any, Wait);
+impl_trivial_from!(ListSchedules, Any, ListSchedules);
+impl_trivial_from!(Reload, Any, Reload);
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Error {
@@ -91,3 +272,25 @@ where
         }
     }
 }
+
+#[test]
+fn test_seconds() {
+    let task = FinishedTask {
+        id: 1,
+        args: vec![],
+        state: TaskState::Finished,
+        attempts: 1,
+        exit_code: Some(0),
+        signal: None,
+        started: None,
+        ended: SystemTime::UNIX_EPOCH + Duration::from_millis(1500),
+    };
+    let json = serde_json::to_value(&task).unwrap();
+    assert_eq!(json["started"], serde_json::Value::Null);
+    assert_eq!(json["ended"], serde_json::json!(1.5));
+    let task: FinishedTask = serde_json::from_value(json).unwrap();
+    assert_eq!(
+        task.ended,
+        SystemTime::UNIX_EPOCH + Duration::from_millis(1500)
+    );
+}
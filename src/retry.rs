@@ -0,0 +1,180 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{anyhow, Error, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::process::Outcome;
+use crate::seconds;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Backoff {
+    Fixed,
+    Exponential,
+}
+
+impl FromStr for Backoff {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "fixed" => Ok(Backoff::Fixed),
+            "exponential" => Ok(Backoff::Exponential),
+            _ => Err(anyhow!("backoff must be either 'fixed' or 'exponential'")),
+        }
+    }
+}
+
+impl fmt::Display for Backoff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Backoff::Fixed => write!(f, "fixed"),
+            Backoff::Exponential => write!(f, "exponential"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RetryOn {
+    Failure,
+    Timeout,
+    ExitCode(i32),
+}
+
+impl FromStr for RetryOn {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "failure" => Ok(RetryOn::Failure),
+            "timeout" => Ok(RetryOn::Timeout),
+            _ => s.parse().map(RetryOn::ExitCode).map_err(|_| {
+                anyhow!("retry condition must be 'failure', 'timeout' or an exit code")
+            }),
+        }
+    }
+}
+
+impl fmt::Display for RetryOn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RetryOn::Failure => write!(f, "failure"),
+            RetryOn::Timeout => write!(f, "timeout"),
+            RetryOn::ExitCode(code) => write!(f, "{}", code),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetryPolicy {
+    pub max_attempts: Option<u32>,
+    #[serde(with = "seconds::option")]
+    pub delay: Option<Duration>,
+    pub backoff: Option<Backoff>,
+    #[serde(default)]
+    pub on: Vec<RetryOn>,
+}
+
+impl RetryPolicy {
+    pub fn or(self, defaults: &RetryPolicy) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: self.max_attempts.or(defaults.max_attempts),
+            delay: self.delay.or(defaults.delay),
+            backoff: self.backoff.or(defaults.backoff),
+            on: if self.on.is_empty() {
+                defaults.on.clone()
+            } else {
+                self.on
+            },
+        }
+    }
+
+    fn is_retryable(&self, outcome: &Outcome) -> bool {
+        let default_on = [RetryOn::Failure];
+        let on = if self.on.is_empty() {
+            &default_on[..]
+        } else {
+            &self.on[..]
+        };
+        on.iter().any(|on| match (on, outcome) {
+            (RetryOn::Failure, Outcome::Exited(status)) => !status.success(),
+            (RetryOn::Failure, Outcome::Failed) => true,
+            (RetryOn::Timeout, Outcome::TimedOut(_)) => true,
+            (RetryOn::ExitCode(code), Outcome::Exited(status)) => status.code() == Some(*code),
+            _ => false,
+        })
+    }
+
+    // Returns the delay before the next attempt if the task should be retried.
+    // `attempt` is the number of the attempt that just finished, starting from one.
+    pub fn retry_delay(&self, attempt: u32, outcome: &Outcome) -> Option<Duration> {
+        if attempt >= self.max_attempts.unwrap_or(1) || !self.is_retryable(outcome) {
+            return None;
+        }
+        let delay = self.delay.unwrap_or_default();
+        match self.backoff.unwrap_or(Backoff::Fixed) {
+            Backoff::Fixed => Some(delay),
+            Backoff::Exponential => {
+                Some(delay.saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1))))
+            }
+        }
+    }
+}
+
+#[test]
+fn test_retry_delay() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    let failed = Outcome::Exited(ExitStatus::from_raw(3 << 8));
+    let succeeded = Outcome::Exited(ExitStatus::from_raw(0));
+    let timed_out = Outcome::TimedOut(None);
+
+    let policy = RetryPolicy::default();
+    assert_eq!(policy.retry_delay(1, &failed), None);
+
+    let policy = RetryPolicy {
+        max_attempts: Some(3),
+        delay: Some(Duration::from_secs(10)),
+        ..Default::default()
+    };
+    assert_eq!(
+        policy.retry_delay(1, &failed),
+        Some(Duration::from_secs(10))
+    );
+    assert_eq!(
+        policy.retry_delay(2, &failed),
+        Some(Duration::from_secs(10))
+    );
+    assert_eq!(policy.retry_delay(3, &failed), None);
+    assert_eq!(policy.retry_delay(1, &succeeded), None);
+    assert_eq!(policy.retry_delay(1, &timed_out), None);
+
+    let policy = RetryPolicy {
+        max_attempts: Some(5),
+        delay: Some(Duration::from_secs(10)),
+        backoff: Some(Backoff::Exponential),
+        on: vec![RetryOn::Timeout, RetryOn::ExitCode(4)],
+    };
+    assert_eq!(policy.retry_delay(1, &failed), None);
+    assert_eq!(
+        policy.retry_delay(1, &timed_out),
+        Some(Duration::from_secs(10))
+    );
+    assert_eq!(
+        policy.retry_delay(3, &timed_out),
+        Some(Duration::from_secs(40))
+    );
+
+    let merged = RetryPolicy {
+        max_attempts: Some(2),
+        ..Default::default()
+    }
+    .or(&policy);
+    assert_eq!(merged.max_attempts, Some(2));
+    assert_eq!(merged.backoff, Some(Backoff::Exponential));
+    assert_eq!(merged.on, policy.on);
+}
@@ -0,0 +1,82 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::sync::{self, Trigger};
+use crate::worker::{Task, TaskQueue};
+
+struct Scheduled {
+    task: Task,
+    at: SystemTime,
+    cancel: Trigger,
+}
+
+#[derive(Clone)]
+pub struct Scheduler {
+    queue: Arc<TaskQueue>,
+    tasks: Arc<Mutex<HashMap<u64, Scheduled>>>,
+    worker_shutdown: Trigger,
+    server_shutdown: Trigger,
+}
+
+impl Scheduler {
+    pub fn new(queue: Arc<TaskQueue>, worker_shutdown: Trigger, server_shutdown: Trigger) -> Self {
+        Self {
+            queue,
+            tasks: Arc::new(Mutex::new(HashMap::new())),
+            worker_shutdown,
+            server_shutdown,
+        }
+    }
+
+    pub fn schedule(&self, task: Task, at: SystemTime) {
+        let id = task.id;
+        let (cancel, mut cancelled) = sync::condition();
+        let mut worker_shutdown = self.worker_shutdown.waiter();
+        let mut server_shutdown = self.server_shutdown.waiter();
+        self.tasks
+            .lock()
+            .unwrap()
+            .insert(id, Scheduled { task, at, cancel });
+        let scheduler = self.clone();
+        tokio::spawn(async move {
+            let delay = at.duration_since(SystemTime::now()).unwrap_or_default();
+            tokio::select! {
+                _ = tokio::time::sleep(delay) => {
+                    // Moved while the queue is locked so that the task is
+                    // always either scheduled or queued
+                    let tasks = &scheduler.tasks;
+                    scheduler
+                        .queue
+                        .push_with(|| Some(tasks.lock().unwrap().remove(&id)?.task))
+                        .await;
+                },
+                _ = cancelled.wait() => {},
+                _ = worker_shutdown.wait() => {},
+                _ = server_shutdown.wait() => {},
+            }
+        });
+    }
+
+    pub fn cancel(&self, id: u64) -> Option<Task> {
+        let scheduled = self.tasks.lock().unwrap().remove(&id)?;
+        scheduled.cancel.set();
+        Some(scheduled.task)
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.tasks.lock().unwrap().contains_key(&id)
+    }
+
+    pub fn collect(&self) -> Vec<(Task, SystemTime)> {
+        let mut tasks = self
+            .tasks
+            .lock()
+            .unwrap()
+            .values()
+            .map(|scheduled| (scheduled.task.clone(), scheduled.at))
+            .collect::<Vec<_>>();
+        tasks.sort_by_key(|(task, at)| (*at, task.id));
+        tasks
+    }
+}
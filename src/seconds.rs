@@ -0,0 +1,103 @@
+//! Durations as seconds and times as seconds since the Unix epoch, so that JSON
+//! output does not depend on how serde represents them.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+pub trait Seconds: Sized {
+    fn to_duration(&self) -> Duration;
+    fn from_duration(duration: Duration) -> Self;
+}
+
+impl Seconds for Duration {
+    fn to_duration(&self) -> Duration {
+        *self
+    }
+
+    fn from_duration(duration: Duration) -> Self {
+        duration
+    }
+}
+
+impl Seconds for SystemTime {
+    fn to_duration(&self) -> Duration {
+        self.duration_since(UNIX_EPOCH).unwrap_or_default()
+    }
+
+    fn from_duration(duration: Duration) -> Self {
+        UNIX_EPOCH + duration
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Repr {
+    Seconds(f64),
+    // Durations journaled before they were written as seconds
+    Serde { secs: u64, nanos: u32 },
+}
+
+impl From<Repr> for Duration {
+    fn from(repr: Repr) -> Self {
+        match repr {
+            Repr::Seconds(seconds) => Duration::try_from_secs_f64(seconds).unwrap_or_default(),
+            Repr::Serde { secs, nanos } => Duration::new(secs, nanos),
+        }
+    }
+}
+
+pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Seconds,
+    S: Serializer,
+{
+    value.to_duration().as_secs_f64().serialize(serializer)
+}
+
+pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: Seconds,
+    D: Deserializer<'de>,
+{
+    Ok(T::from_duration(Repr::deserialize(deserializer)?.into()))
+}
+
+pub mod option {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use super::{Repr, Seconds};
+
+    pub fn serialize<T, S>(value: &Option<T>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Seconds,
+        S: Serializer,
+    {
+        value
+            .as_ref()
+            .map(|value| value.to_duration().as_secs_f64())
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        T: Seconds,
+        D: Deserializer<'de>,
+    {
+        let repr = Option::<Repr>::deserialize(deserializer)?;
+        Ok(repr.map(|repr| T::from_duration(repr.into())))
+    }
+}
+
+#[test]
+fn test_legacy_duration() {
+    #[derive(Deserialize)]
+    struct Policy {
+        #[serde(with = "option")]
+        delay: Option<Duration>,
+    }
+    let policy: Policy = serde_json::from_str(r#"{"delay":{"secs":10,"nanos":5}}"#).unwrap();
+    assert_eq!(policy.delay, Some(Duration::new(10, 5)));
+    let policy: Policy = serde_json::from_str(r#"{"delay":2.5}"#).unwrap();
+    assert_eq!(policy.delay, Some(Duration::from_millis(2500)));
+}
@@ -1,57 +1,1054 @@
 use anyhow::{bail, Result};
-use log::{error, info};
-use std::collections::HashMap;
+use log::{error, info, warn};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
 use tokio::net::{UnixListener, UnixStream};
-use tokio::sync::Mutex;
+use tokio::sync::broadcast::{
+    self,
+    error::{RecvError, TryRecvError},
+};
+use tokio::sync::{watch, Mutex};
 
+use crate::config::{Config, QueueConfig};
 use crate::connection::Connection;
-use crate::request::{self, Request};
-use crate::response::{self, Response};
-use crate::sync::{condition, DropGuard, DropWaiter, Trigger};
-use crate::template::Template;
-use crate::worker::{Task, TaskQueue, Worker};
+use crate::journal::{self, Entry, Journal, QueueState};
+use crate::output::Output;
+use crate::request::{self, Dependency, Request, Stdin};
+use crate::response::{self, Response, Stream, TaskState};
+use crate::scheduler::Scheduler;
+use crate::spool::{Capture, Lines, Spool};
+use crate::sync::{condition, DropGuard, DropWaiter, Limit, Trigger};
+use crate::worker::{
+    Completions, Defaults, Finished, History, RunningTasks, Settings, Starts, Task, TaskQueue,
+    Worker,
+};
+
+const COMPLETIONS_CAPACITY: usize = 1024;
+const SPOOL_DIR: &str = "spool";
 
 struct WorkerHandle {
     queue: Arc<TaskQueue>,
-    template: Option<Template>,
+    running: RunningTasks,
+    history: Arc<History>,
+    scheduler: Scheduler,
+    defaults: Arc<std::sync::Mutex<Defaults>>,
+    max_parallel: Arc<Limit>,
+    // Current settings of the queue
+    config: request::CreateQueue,
+    created: Instant,
+    paused: Arc<watch::Sender<bool>>,
+    schedules: HashMap<u64, ScheduleHandle>,
+    blocked: HashMap<u64, Blocked>,
+    // Created from the configuration file
+    configured: bool,
+    // Removed from the configuration file, removed once its tasks finish
+    retiring: bool,
     shutdown: Trigger,
 }
 
+struct Blocked {
+    task: Task,
+    waiting: Vec<Dependency>,
+}
+
+/// Parts of a queue needed for finding its tasks without holding the lock
+/// of all queues.
+struct TaskLookup {
+    queue: Arc<TaskQueue>,
+    running: RunningTasks,
+    scheduler: Scheduler,
+    history: Arc<History>,
+    blocked: HashSet<u64>,
+}
+
+enum Progress {
+    Pending,
+    Done(TaskState),
+    Unknown,
+}
+
+enum Resolution {
+    Ready,
+    Waiting(Vec<Dependency>),
+    Failed(u64, Option<TaskState>),
+}
+
+/// Change of a queue made by reloading the configuration file, prepared
+/// before any change is applied.
+enum Reloaded {
+    Updated {
+        name: String,
+        update: request::UpdateQueue,
+        output: Option<Output>,
+        schedules: Option<Vec<request::AddSchedule>>,
+    },
+    Created {
+        worker: WorkerHandle,
+        schedules: Vec<request::AddSchedule>,
+    },
+}
+
+struct ScheduleHandle {
+    config: request::AddSchedule,
+    next: Arc<std::sync::Mutex<Option<SystemTime>>>,
+    cancel: Trigger,
+    // Added from the configuration file and not journaled
+    configured: bool,
+}
+
 impl WorkerHandle {
     fn expand_args(&self, args: Vec<String>) -> Result<Vec<String>> {
-        if let Some(template) = &self.template {
+        if let Some(template) = &self.config.template {
             template.instantiate(args)
         } else {
             Ok(args)
         }
     }
+
+    async fn describe(&self) -> response::Queue {
+        let config = &self.config;
+        let statistics = self.history.statistics();
+        let queued = self.queue.len().await + self.scheduler.collect().len() + self.blocked.len();
+        response::Queue {
+            name: config.name.clone(),
+            paused: *self.paused.borrow(),
+            retiring: self.retiring,
+            max_parallel: config.max_parallel,
+            output: config.output.clone(),
+            timeout: config.timeout,
+            dir: config.dir.clone(),
+            template: config
+                .template
+                .as_ref()
+                .map(|template| template.to_string()),
+            retry: config.retry.clone(),
+            kill: config.kill,
+            env: config.env.clone(),
+            history_size: config.history_size,
+            stats: response::QueueStats {
+                queued,
+                running: self.running.lock().unwrap().len(),
+                completed: statistics.completed,
+                failed: statistics.failed,
+                average_runtime: statistics.average_runtime(),
+                uptime: self.created.elapsed(),
+            },
+        }
+    }
+
+    async fn submit(&self, task: Task) {
+        match task.at {
+            Some(at) if at > SystemTime::now() => self.scheduler.schedule(task, at),
+            _ => self.queue.push(task).await,
+        }
+    }
+
+    /// Submits the task once all of its dependencies have finished.
+    async fn start(&mut self, task: Task, waiting: Vec<Dependency>) {
+        if waiting.is_empty() {
+            self.submit(task).await;
+        } else {
+            self.blocked.insert(task.id, Blocked { task, waiting });
+        }
+    }
+
+    async fn is_idle(&self) -> bool {
+        if self.queue.len().await > 0 {
+            return false;
+        }
+        self.blocked.is_empty()
+            && self.scheduler.collect().is_empty()
+            && self.running.lock().unwrap().is_empty()
+    }
+
+    fn configured_schedules(&self) -> Vec<&request::AddSchedule> {
+        let mut schedules: Vec<_> = self
+            .schedules
+            .iter()
+            .filter(|(_, schedule)| schedule.configured)
+            .collect();
+        schedules.sort_by_key(|(id, _)| **id);
+        schedules
+            .into_iter()
+            .map(|(_, schedule)| &schedule.config)
+            .collect()
+    }
+
+    fn lookup(&self) -> TaskLookup {
+        TaskLookup {
+            queue: self.queue.clone(),
+            running: self.running.clone(),
+            scheduler: self.scheduler.clone(),
+            history: self.history.clone(),
+            blocked: self.blocked.keys().cloned().collect(),
+        }
+    }
+}
+
+impl TaskLookup {
+    async fn contains(&self, id: u64) -> bool {
+        // Tasks only move between the queue, running tasks and the scheduler
+        // while the queue is locked
+        self.blocked.contains(&id)
+            || self
+                .queue
+                .inspect(|tasks| {
+                    tasks.iter().any(|task| task.id == id)
+                        || self.running.lock().unwrap().contains_key(&id)
+                        || self.scheduler.contains(id)
+                })
+                .await
+    }
 }
 
-type QueueMap = Arc<Mutex<HashMap<String, WorkerHandle>>>;
+/// Removes dependencies on task `id` that finished in `state`, returns false
+/// if they are not satisfied.
+fn dependency_done(waiting: &mut Vec<Dependency>, id: u64, state: TaskState) -> bool {
+    let satisfied = waiting
+        .iter()
+        .filter(|dependency| dependency.id == id)
+        .all(|dependency| dependency.any || state == TaskState::Finished);
+    if satisfied {
+        waiting.retain(|dependency| dependency.id != id);
+    }
+    satisfied
+}
+
+struct State {
+    queues: Mutex<HashMap<String, WorkerHandle>>,
+    journal: Arc<Journal>,
+    next_id: AtomicU64,
+    next_schedule_id: AtomicU64,
+    completions: Completions,
+    starts: Starts,
+    spool: Arc<Spool>,
+    // Final states of tasks restored from the journal
+    outcomes: std::sync::Mutex<HashMap<u64, TaskState>>,
+    // Handed out to workers, taken on shutdown to wait for their processes
+    processes: std::sync::Mutex<Option<DropGuard>>,
+    // Configuration file the server was started with
+    config: Option<PathBuf>,
+    // Set when the server stops accepting tasks before shutting down
+    draining: AtomicBool,
+    shutdown: Trigger,
+}
+
+impl State {
+    fn check_accepting(&self) -> Result<()> {
+        if self.draining.load(Ordering::SeqCst) {
+            bail!("server is shutting down");
+        }
+        Ok(())
+    }
+
+    /// Sends a task, `id` is used instead of allocating a new one if given.
+    async fn send(&self, req: request::Send, id: Option<u64>) -> Result<response::Send> {
+        self.check_accepting()?;
+        // Subscribed before resolving dependencies so that dependencies
+        // finishing meanwhile are not missed
+        let mut completions = self.completions.subscribe();
+        let (task, lookups) = {
+            let map = self.queues.lock().await;
+            let worker = match map.get(&req.name) {
+                Some(worker) if worker.retiring => {
+                    bail!("queue '{}' is being removed", &req.name)
+                }
+                Some(worker) => worker,
+                None => bail!("queue '{}' does not exist", &req.name),
+            };
+            let mut args = worker.expand_args(req.args)?;
+            if args.is_empty() {
+                bail!("command cannot be empty");
+            }
+            let binary = args.remove(0);
+
+            let id = id.unwrap_or_else(|| self.next_id.fetch_add(1, Ordering::SeqCst));
+            let task = Task {
+                id,
+                binary,
+                timeout: req.timeout,
+                dir: req.dir,
+                cwd: req.cwd,
+                args,
+                priority: req.priority,
+                at: req.at,
+                after: req.after,
+                stdin: req.stdin,
+                retry: req.retry,
+                kill: req.kill,
+                env: req.env,
+                attempts: 0,
+            };
+            (task, State::lookups(&map))
+        };
+        let resolution = self.resolve_dependencies(&lookups, &task).await;
+        let mut map = self.queues.lock().await;
+        let resolution = match resolution {
+            Resolution::Waiting(mut waiting) => loop {
+                match completions.try_recv() {
+                    Ok(finished) => {
+                        let (id, state) = (finished.task.id, finished.state);
+                        if !dependency_done(&mut waiting, id, state) {
+                            break Resolution::Failed(id, Some(state));
+                        }
+                    }
+                    Err(TryRecvError::Lagged(_)) => {
+                        break self
+                            .resolve_dependencies(&State::lookups(&map), &task)
+                            .await
+                    }
+                    Err(_) if waiting.is_empty() => break Resolution::Ready,
+                    Err(_) => break Resolution::Waiting(waiting),
+                }
+            },
+            resolution => resolution,
+        };
+        let waiting = match resolution {
+            Resolution::Ready => vec![],
+            Resolution::Waiting(waiting) => waiting,
+            Resolution::Failed(dependency, Some(state)) => {
+                bail!("dependency {} has {}", dependency, state)
+            }
+            Resolution::Failed(dependency, None) => {
+                bail!("dependency {} does not exist", dependency)
+            }
+        };
+        let worker = match map.get_mut(&req.name) {
+            Some(worker) if worker.retiring => bail!("queue '{}' is being removed", &req.name),
+            Some(worker) => worker,
+            None => bail!("queue '{}' does not exist", &req.name),
+        };
+        let id = task.id;
+        info!("received task {} '{}'", id, task.to_string());
+        self.journal.append(&Entry::Send {
+            queue: req.name.clone(),
+            task: task.clone(),
+        })?;
+        worker.start(task, waiting).await;
+        Ok(response::Send { id })
+    }
+
+    fn lookups(map: &HashMap<String, WorkerHandle>) -> Vec<TaskLookup> {
+        map.values().map(WorkerHandle::lookup).collect()
+    }
+
+    async fn progress(&self, lookups: &[TaskLookup], id: u64) -> Progress {
+        let finished = || {
+            lookups
+                .iter()
+                .find_map(|lookup| lookup.history.find(id))
+                .map(|finished| finished.state)
+                .or_else(|| self.outcomes.lock().unwrap().get(&id).cloned())
+        };
+        if let Some(state) = finished() {
+            return Progress::Done(state);
+        }
+        for lookup in lookups {
+            if lookup.contains(id).await {
+                return Progress::Pending;
+            }
+        }
+        // The task might have finished after we looked at the history
+        if let Some(state) = finished() {
+            return Progress::Done(state);
+        }
+        // Tasks no longer in history are only found in the journal
+        match self.journal.outcome(id) {
+            Ok(Some(state)) => Progress::Done(state),
+            Ok(None) => Progress::Unknown,
+            Err(err) => {
+                error!("error reading journal: {}", err);
+                Progress::Unknown
+            }
+        }
+    }
+
+    async fn resolve_dependencies(&self, lookups: &[TaskLookup], task: &Task) -> Resolution {
+        let mut waiting = vec![];
+        for dependency in task.after.iter() {
+            match self.progress(lookups, dependency.id).await {
+                Progress::Pending => waiting.push(*dependency),
+                Progress::Done(state) if dependency.any || state == TaskState::Finished => {}
+                Progress::Done(state) => return Resolution::Failed(dependency.id, Some(state)),
+                Progress::Unknown => return Resolution::Failed(dependency.id, None),
+            }
+        }
+        if waiting.is_empty() {
+            Resolution::Ready
+        } else {
+            Resolution::Waiting(waiting)
+        }
+    }
+
+    fn cancel_blocked(&self, worker: &WorkerHandle, task: Task) {
+        worker.history.cancel(task);
+    }
+
+    /// Starts or cancels tasks waiting for task `id`, `state` is `None` if
+    /// the outcome of the task is not known.
+    async fn dependency_finished(&self, id: u64, state: Option<TaskState>) {
+        let mut map = self.queues.lock().await;
+        for worker in map.values_mut() {
+            let dependents: Vec<u64> = worker
+                .blocked
+                .values()
+                .filter(|blocked| blocked.waiting.iter().any(|dependency| dependency.id == id))
+                .map(|blocked| blocked.task.id)
+                .collect();
+            for dependent in dependents {
+                let mut blocked = match worker.blocked.remove(&dependent) {
+                    Some(blocked) => blocked,
+                    None => continue,
+                };
+                match state {
+                    Some(state) if dependency_done(&mut blocked.waiting, id, state) => {
+                        worker.start(blocked.task, blocked.waiting).await;
+                        continue;
+                    }
+                    Some(state) => info!(
+                        "task {} cancelled, dependency {} has {}",
+                        dependent, id, state
+                    ),
+                    None => warn!(
+                        "task {} cancelled, outcome of dependency {} is unknown",
+                        dependent, id
+                    ),
+                }
+                self.cancel_blocked(worker, blocked.task);
+            }
+        }
+    }
+
+    async fn recheck_dependencies(&self) {
+        let (ids, lookups) = {
+            let map = self.queues.lock().await;
+            let mut ids: Vec<u64> = map
+                .values()
+                .flat_map(|worker| worker.blocked.values())
+                .flat_map(|blocked| blocked.waiting.iter().map(|dependency| dependency.id))
+                .collect();
+            ids.sort_unstable();
+            ids.dedup();
+            (ids, State::lookups(&map))
+        };
+        let mut finished = vec![];
+        for id in ids {
+            match self.progress(&lookups, id).await {
+                Progress::Pending => {}
+                Progress::Done(state) => finished.push((id, Some(state))),
+                Progress::Unknown => finished.push((id, None)),
+            }
+        }
+        for (id, state) in finished {
+            self.dependency_finished(id, state).await;
+        }
+    }
+
+    async fn resolve_blocked(&self, mut completions: broadcast::Receiver<Finished>) {
+        let mut shutdown = self.shutdown.waiter();
+        loop {
+            let finished = tokio::select! {
+                finished = completions.recv() => finished,
+                _ = shutdown.wait() => break,
+            };
+            match finished {
+                Ok(finished) => {
+                    self.dependency_finished(finished.task.id, Some(finished.state))
+                        .await
+                }
+                Err(RecvError::Lagged(_)) => self.recheck_dependencies().await,
+                Err(RecvError::Closed) => break,
+            }
+        }
+    }
+
+    fn spawn_schedule(
+        state: &Arc<State>,
+        worker: &WorkerHandle,
+        id: u64,
+        config: request::AddSchedule,
+    ) -> ScheduleHandle {
+        let (cancel, mut cancelled) = condition();
+        let mut worker_shutdown = worker.shutdown.waiter();
+        let mut server_shutdown = state.shutdown.waiter();
+        let next = Arc::new(std::sync::Mutex::new(None));
+        let handle = ScheduleHandle {
+            config: config.clone(),
+            next: next.clone(),
+            cancel,
+            configured: false,
+        };
+        let state = state.clone();
+        tokio::spawn(async move {
+            let mut previous = None;
+            let mut base = SystemTime::now();
+            loop {
+                let now = SystemTime::now();
+                // Firings missed while the server was busy are skipped
+                let at = match config.when.next_after(base) {
+                    Some(at) if at < now => config.when.next_after(now),
+                    at => at,
+                };
+                let at = match at {
+                    Some(at) => at,
+                    None => {
+                        warn!("schedule {} will never run again", id);
+                        break;
+                    }
+                };
+                *next.lock().unwrap() = Some(at);
+                let delay = at.duration_since(now).unwrap_or_default();
+                tokio::select! {
+                    _ = tokio::time::sleep(delay) => {},
+                    _ = cancelled.wait() => break,
+                    _ = worker_shutdown.wait() => break,
+                    _ = server_shutdown.wait() => break,
+                }
+                base = at;
+                if let Some(previous) = previous {
+                    if config.skip_if_running && state.is_pending(previous).await {
+                        info!(
+                            "schedule {} skipped, task {} has not finished",
+                            id, previous
+                        );
+                        continue;
+                    }
+                }
+                let req = request::Send {
+                    name: config.name.clone(),
+                    timeout: config.timeout,
+                    dir: config.dir.clone(),
+                    cwd: config.cwd.clone(),
+                    args: config.args.clone(),
+                    priority: config.priority,
+                    at: None,
+                    after: vec![],
+                    stdin: Stdin::Null,
+                    retry: config.retry.clone(),
+                    kill: config.kill,
+                    env: config.env.clone(),
+                };
+                match state.send(req, None).await {
+                    Ok(resp) => previous = Some(resp.id),
+                    Err(err) => error!("schedule {} failed to send task: {}", id, err),
+                }
+            }
+            *next.lock().unwrap() = None;
+        });
+        handle
+    }
+
+    fn spawn_worker(&self, config: request::CreateQueue, paused: bool) -> Result<WorkerHandle> {
+        let processes = match self.processes.lock().unwrap().clone() {
+            Some(processes) => processes,
+            None => bail!("server is shutting down"),
+        };
+        let queue = Arc::new(TaskQueue::new());
+        let settings = Settings {
+            name: config.name.clone(),
+            max_parallel: config.max_parallel,
+            output: config.output.clone(),
+            timeout: config.timeout,
+            dir: config.dir.clone(),
+            retry: config.retry.clone(),
+            kill: config.kill,
+            env: config.env.clone(),
+            history_size: config.history_size,
+            paused,
+        };
+        let worker = Worker::new(
+            queue.clone(),
+            settings,
+            self.shutdown.clone(),
+            self.journal.clone(),
+            self.completions.clone(),
+            self.starts.clone(),
+            self.spool.clone(),
+            processes,
+        )?;
+        let worker_handle = WorkerHandle {
+            queue,
+            running: worker.running(),
+            history: worker.history(),
+            scheduler: worker.scheduler(),
+            defaults: worker.defaults(),
+            max_parallel: worker.max_parallel(),
+            config,
+            created: Instant::now(),
+            paused: worker.pause_notifier(),
+            schedules: HashMap::new(),
+            blocked: HashMap::new(),
+            configured: false,
+            retiring: false,
+            shutdown: worker.shutdown_notifer(),
+        };
+
+        tokio::spawn(async move {
+            worker.process().await;
+        });
+
+        Ok(worker_handle)
+    }
+
+    fn create_queue(&self, config: request::CreateQueue) -> Result<WorkerHandle> {
+        let worker_handle = self.spawn_worker(config, false)?;
+        self.add_queue(worker_handle)
+    }
+
+    /// Journals a queue whose worker is already spawned.
+    fn add_queue(&self, worker_handle: WorkerHandle) -> Result<WorkerHandle> {
+        let config = &worker_handle.config;
+        if let Err(err) = self.journal.append(&Entry::CreateQueue(config.clone())) {
+            worker_handle.shutdown.set();
+            return Err(err.into());
+        }
+        info!("queue '{}' created", config.name);
+        Ok(worker_handle)
+    }
+
+    fn update_queue(&self, worker: &mut WorkerHandle, req: request::UpdateQueue) -> Result<()> {
+        let output = State::open_output(&req)?;
+        self.apply_update(worker, req, output)
+    }
+
+    fn open_output(req: &request::UpdateQueue) -> Result<Option<Output>> {
+        Ok(match &req.output {
+            Some(Some(path)) => Some(Output::file(path)?),
+            Some(None) => Some(Output::new(io::stdout())),
+            None => None,
+        })
+    }
+
+    fn apply_update(
+        &self,
+        worker: &mut WorkerHandle,
+        req: request::UpdateQueue,
+        output: Option<Output>,
+    ) -> Result<()> {
+        self.journal.append(&Entry::UpdateQueue(req.clone()))?;
+        worker.config.update(&req);
+        if let Some(max_parallel) = req.max_parallel {
+            worker.max_parallel.set(max_parallel);
+        }
+        if let Some(history_size) = req.history_size {
+            worker.history.resize(history_size);
+        }
+        {
+            let mut defaults = worker.defaults.lock().unwrap();
+            if let Some(output) = output {
+                defaults.output = output;
+            }
+            if let Some(timeout) = req.timeout {
+                defaults.timeout = timeout;
+            }
+            if let Some(dir) = req.dir {
+                defaults.dir = dir;
+            }
+            if let Some(retry) = req.retry {
+                defaults.retry = retry;
+            }
+            if let Some(kill) = req.kill {
+                defaults.kill = kill;
+            }
+            if let Some(env) = req.env {
+                defaults.env = env;
+            }
+        }
+        info!("queue '{}' updated", req.name);
+        Ok(())
+    }
+
+    fn add_configured_schedules(
+        state: &Arc<State>,
+        worker: &mut WorkerHandle,
+        schedules: Vec<request::AddSchedule>,
+    ) {
+        for config in schedules {
+            let id = state.next_schedule_id.fetch_add(1, Ordering::SeqCst);
+            info!(
+                "schedule {} '{}' added to queue '{}'",
+                id, config.when, config.name
+            );
+            let mut schedule = State::spawn_schedule(state, worker, id, config);
+            schedule.configured = true;
+            worker.schedules.insert(id, schedule);
+        }
+    }
+
+    fn configure_queue(&self, worker: &mut WorkerHandle) -> Result<()> {
+        if !worker.configured {
+            self.journal.append(&Entry::ConfigureQueue {
+                name: worker.config.name.clone(),
+            })?;
+            worker.configured = true;
+        }
+        Ok(())
+    }
+
+    /// Stops adding tasks to a queue removed from the configuration file and
+    /// removes the queue once its remaining tasks have finished.
+    fn retire(state: &Arc<State>, worker: &mut WorkerHandle) {
+        worker.retiring = true;
+        // Tasks of a paused queue would never finish
+        if *worker.paused.borrow() {
+            let req = request::ResumeQueue {
+                name: worker.config.name.clone(),
+            };
+            if let Err(err) = state.journal.append(&Entry::ResumeQueue(req)) {
+                error!("error writing journal: {}", err);
+            }
+            let _ = worker.paused.send(false);
+            info!("queue '{}' resumed", worker.config.name);
+        }
+        for (id, schedule) in worker.schedules.drain() {
+            schedule.cancel.set();
+            if !schedule.configured {
+                if let Err(err) = state.journal.append(&Entry::RemoveSchedule { id }) {
+                    error!("error writing journal: {}", err);
+                }
+            }
+        }
+        info!("queue '{}' retiring", worker.config.name);
+        let name = worker.config.name.clone();
+        let mut completions = state.completions.subscribe();
+        let state = state.clone();
+        tokio::spawn(async move {
+            while !state.remove_retired(&name).await {
+                if let Err(RecvError::Closed) = completions.recv().await {
+                    break;
+                }
+            }
+        });
+    }
+
+    // Returns false while the queue is retiring and has unfinished tasks
+    async fn remove_retired(&self, name: &str) -> bool {
+        let mut map = self.queues.lock().await;
+        match map.get(name) {
+            Some(worker) if worker.retiring => {
+                if !worker.is_idle().await {
+                    return false;
+                }
+            }
+            _ => return true,
+        }
+        let req = request::RemoveQueue {
+            name: name.to_string(),
+        };
+        if let Err(err) = self.journal.append(&Entry::RemoveQueue(req)) {
+            // The queue is retired again when the server is restarted
+            error!("error writing journal: {}", err);
+            return true;
+        }
+        if let Some(worker) = map.remove(name) {
+            worker.shutdown.set();
+            worker.history.resize(0);
+        }
+        info!("queue '{}' removed", name);
+        true
+    }
+
+    /// Applies the configuration file again: new queues are created, changed
+    /// settings are updated and queues removed from the file are retired.
+    async fn reload(state: &Arc<State>) -> Result<response::Reload> {
+        state.check_accepting()?;
+        let path = match &state.config {
+            Some(path) => path,
+            None => bail!("server was started without a configuration file"),
+        };
+        let config = Config::load(path)?;
+        let mut map = state.queues.lock().await;
+
+        // Everything that can fail is prepared first so that an invalid
+        // queue leaves all of them unchanged
+        let mut prepared = vec![];
+        let mut names = HashSet::new();
+        for QueueConfig { queue, schedules } in config.queues {
+            let name = queue.name.clone();
+            names.insert(name.clone());
+            let reloaded = match map.get(&name) {
+                Some(worker) => {
+                    let update = worker.config.changes(&queue);
+                    let schedules_changed =
+                        worker.configured_schedules() != schedules.iter().collect::<Vec<_>>();
+                    if update.is_empty()
+                        && !schedules_changed
+                        && worker.configured
+                        && !worker.retiring
+                    {
+                        continue;
+                    }
+                    State::open_output(&update).map(|output| Reloaded::Updated {
+                        name,
+                        update,
+                        output,
+                        schedules: Some(schedules).filter(|_| schedules_changed),
+                    })
+                }
+                None => state
+                    .spawn_worker(queue, false)
+                    .map(|worker| Reloaded::Created { worker, schedules }),
+            };
+            match reloaded {
+                Ok(reloaded) => prepared.push(reloaded),
+                Err(err) => {
+                    State::discard_reloaded(prepared);
+                    return Err(err);
+                }
+            }
+        }
+
+        let mut changes = response::Reload::default();
+        let mut prepared = prepared.into_iter();
+        while let Some(reloaded) = prepared.next() {
+            if let Err(err) = state.apply_reloaded(&mut map, reloaded, &mut changes) {
+                // Only writing the journal can fail here
+                State::discard_reloaded(prepared);
+                return Err(err);
+            }
+        }
+        for (name, worker) in map.iter_mut() {
+            if worker.configured && !worker.retiring && !names.contains(name) {
+                State::retire(state, worker);
+                changes.retired.push(name.clone());
+            }
+        }
+        changes.retired.sort();
+        info!("configuration '{}' reloaded", path.display());
+        Ok(changes)
+    }
+
+    fn apply_reloaded(
+        self: &Arc<Self>,
+        map: &mut HashMap<String, WorkerHandle>,
+        reloaded: Reloaded,
+        changes: &mut response::Reload,
+    ) -> Result<()> {
+        match reloaded {
+            Reloaded::Updated {
+                name,
+                update,
+                output,
+                schedules,
+            } => {
+                let worker = map.get_mut(&name).unwrap();
+                if !update.is_empty() {
+                    self.apply_update(worker, update, output)?;
+                }
+                self.configure_queue(worker)?;
+                if let Some(schedules) = schedules {
+                    worker.schedules.retain(|_, schedule| {
+                        if schedule.configured {
+                            schedule.cancel.set();
+                        }
+                        !schedule.configured
+                    });
+                    State::add_configured_schedules(self, worker, schedules);
+                }
+                worker.retiring = false;
+                changes.updated.push(name);
+            }
+            Reloaded::Created { worker, schedules } => {
+                let mut worker = self.add_queue(worker)?;
+                let name = worker.config.name.clone();
+                if let Err(err) = self.configure_queue(&mut worker) {
+                    // The queue is journaled already
+                    error!("error writing journal: {}", err);
+                }
+                State::add_configured_schedules(self, &mut worker, schedules);
+                map.insert(name.clone(), worker);
+                changes.created.push(name);
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops workers spawned for queues that were not created.
+    fn discard_reloaded<I>(reloaded: I)
+    where
+        I: IntoIterator<Item = Reloaded>,
+    {
+        for reloaded in reloaded {
+            if let Reloaded::Created { worker, .. } = reloaded {
+                worker.shutdown.set();
+            }
+        }
+    }
+
+    /// Stops accepting tasks and waits until no tasks are running, and with
+    /// `queued` until the active queues are empty, or until `timeout` passes.
+    async fn drain(&self, queued: bool, timeout: Option<Duration>) {
+        info!("draining tasks before shutdown");
+        self.draining.store(true, Ordering::SeqCst);
+        let mut completions = self.completions.subscribe();
+        {
+            let map = self.queues.lock().await;
+            for worker in map.values() {
+                for schedule in worker.schedules.values() {
+                    schedule.cancel.set();
+                }
+                // Not journaled, the tasks are started when the server is
+                // started again
+                if !queued {
+                    let _ = worker.paused.send(true);
+                }
+            }
+        }
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        while !self.is_drained(queued).await {
+            let expired = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                result = completions.recv() => {
+                    if let Err(RecvError::Closed) = result {
+                        break;
+                    }
+                }
+                _ = expired => {
+                    warn!("tasks did not finish in time, stopping them");
+                    break;
+                }
+            }
+        }
+    }
+
+    async fn is_drained(&self, queued: bool) -> bool {
+        let map = self.queues.lock().await;
+        for worker in map.values() {
+            let running = worker.running.lock().unwrap().len();
+            if running > 0 {
+                return false;
+            }
+            // Delayed tasks and tasks waiting for dependencies are pending
+            // as well, like in `is_idle`
+            if queued && !*worker.paused.borrow() && !worker.is_idle().await {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Answers waiting for a task that is no longer in history from the
+    /// journal, like `progress` does.
+    fn journaled_wait(&self, id: u64) -> Result<response::Wait> {
+        if let Some(finished) = self.journal.finished(id)? {
+            let tasks = vec![response::FinishedTask::from(&finished)];
+            return Ok(response::Wait { tasks });
+        }
+        let state = self.outcomes.lock().unwrap().get(&id).cloned();
+        let state = match state {
+            Some(state) => state,
+            None => match self.journal.outcome(id)? {
+                Some(state) => state,
+                None => bail!("task {} does not exist", id),
+            },
+        };
+        // Only the final state is kept of tasks needed by dependencies
+        let task = response::FinishedTask {
+            id,
+            args: vec![],
+            state,
+            attempts: 0,
+            exit_code: None,
+            signal: None,
+            started: None,
+            ended: SystemTime::UNIX_EPOCH,
+        };
+        Ok(response::Wait { tasks: vec![task] })
+    }
+
+    async fn find_finished(&self, id: u64) -> Option<Finished> {
+        let map = self.queues.lock().await;
+        map.values().find_map(|worker| worker.history.find(id))
+    }
+
+    async fn find_capture(&self, id: u64) -> Option<Arc<Capture>> {
+        let map = self.queues.lock().await;
+        map.values().find_map(|worker| {
+            worker
+                .running
+                .lock()
+                .unwrap()
+                .get(&id)
+                .map(|running| running.capture.clone())
+        })
+    }
+
+    async fn is_pending(&self, id: u64) -> bool {
+        let lookups = State::lookups(&*self.queues.lock().await);
+        for lookup in lookups {
+            if lookup.contains(id).await {
+                return true;
+            }
+        }
+        false
+    }
+
+    async fn wait_for(
+        &self,
+        mut completions: broadcast::Receiver<Finished>,
+        mut pending: HashSet<u64>,
+        mut finished: Vec<Finished>,
+    ) -> Result<response::Wait> {
+        while !pending.is_empty() {
+            match completions.recv().await {
+                Ok(task) => {
+                    if pending.remove(&task.task.id) {
+                        finished.push(task);
+                    }
+                }
+                Err(RecvError::Lagged(_)) => {
+                    // Tasks are recorded in history before they stop being
+                    // pending so they have to be looked up in this order
+                    for id in pending.iter().cloned().collect::<Vec<_>>() {
+                        if self.is_pending(id).await {
+                            continue;
+                        }
+                        pending.remove(&id);
+                        if let Some(task) = self.find_finished(id).await {
+                            finished.push(task);
+                        }
+                    }
+                }
+                Err(RecvError::Closed) => bail!("server is shutting down"),
+            }
+        }
+        finished.sort_by_key(|task| task.task.id);
+        let tasks = finished.iter().map(response::FinishedTask::from).collect();
+        Ok(response::Wait { tasks })
+    }
+}
 
 struct ClientHandler {
     connection: Connection,
-    queues: QueueMap,
+    state: Arc<State>,
     shutdown_requested: bool,
     shutdown: Trigger,
+    // Completions since the last task sent on this connection, so that
+    // `send --wait` works even when the queue keeps no history
+    sent: Option<(u64, broadcast::Receiver<Finished>)>,
     _shutdown_sentinel: DropGuard,
 }
 
 impl ClientHandler {
-    pub fn new(
-        connection: UnixStream,
-        queues: QueueMap,
-        shutdown: Trigger,
-        shutdown_sentinel: DropGuard,
-    ) -> Self {
+    pub fn new(connection: UnixStream, state: Arc<State>, shutdown_sentinel: DropGuard) -> Self {
         let connection = Connection::new(connection);
         let shutdown_requested = false;
+        let shutdown = state.shutdown.clone();
         ClientHandler {
             connection,
-            queues,
+            state,
             shutdown_requested,
             shutdown,
+            sent: None,
             _shutdown_sentinel: shutdown_sentinel,
         }
     }
@@ -61,7 +1058,7 @@ impl ClientHandler {
         tokio::select! {
             ret = self.serve_inner() => {
                 if self.shutdown_requested {
-                    let _ = self.shutdown.set();
+                    self.shutdown.set();
                 }
                 ret
             },
@@ -75,140 +1072,692 @@ impl ClientHandler {
                 Some(message) => message,
                 None => return Ok(()),
             };
-            let resp = self.handle_request(request).await;
-            self.connection.write_message(&resp).await?;
+            match request {
+                Request::FollowLogs(req) => self.handle_follow_logs(req).await?,
+                Request::Logs(req) => self.handle_logs(req).await?,
+                request => {
+                    let resp = self.handle_request(request).await;
+                    self.connection.write_message(&resp).await?;
+                }
+            }
         }
         Ok(())
     }
 
     async fn handle_request(&mut self, req: Request) -> Response {
         match req {
-            Request::StopServer => self.handle_stop_server().await.into(),
+            Request::StopServer(req) => self.handle_stop_server(req).await.into(),
             Request::CreateQueue(req) => self.handle_create_queue(req).await.into(),
             Request::RemoveQueue(req) => self.handle_remove_queue(req).await.into(),
+            Request::UpdateQueue(req) => self.handle_update_queue(req).await.into(),
             Request::Send(req) => self.handle_send(req).await.into(),
             Request::ListQueues => self.handle_list_queues().await.into(),
+            Request::ShowQueue(req) => self.handle_show_queue(req).await.into(),
             Request::ListTasks(req) => self.handle_list_tasks(req).await.into(),
+            Request::CancelTask(req) => self.handle_cancel_task(req).await.into(),
+            Request::History(req) => self.handle_history(req).await.into(),
+            Request::WaitTask(req) => self.handle_wait_task(req).await.into(),
+            Request::WaitQueue(req) => self.handle_wait_queue(req).await.into(),
+            Request::PauseQueue(req) => self.handle_pause_queue(req).await.into(),
+            Request::ResumeQueue(req) => self.handle_resume_queue(req).await.into(),
+            Request::Logs(_) | Request::FollowLogs(_) => unreachable!(),
+            Request::AddSchedule(req) => self.handle_add_schedule(req).await.into(),
+            Request::ListSchedules(req) => self.handle_list_schedules(req).await.into(),
+            Request::RemoveSchedule(req) => self.handle_remove_schedule(req).await.into(),
+            Request::Reload => State::reload(&self.state).await.into(),
         }
     }
 
-    async fn handle_stop_server(&mut self) -> Result<response::Empty> {
+    async fn handle_stop_server(&mut self, req: request::StopServer) -> Result<response::Empty> {
+        if req.drain {
+            self.state.drain(req.queued, req.timeout).await;
+        }
         info!("shutdown requested");
         self.shutdown_requested = true;
         response::ok()
     }
 
     async fn handle_create_queue(&self, req: request::CreateQueue) -> Result<response::Empty> {
-        let mut map = self.queues.lock().await;
+        self.state.check_accepting()?;
+        let mut map = self.state.queues.lock().await;
         if map.contains_key(&req.name) {
             bail!("queue '{}' already exists", &req.name);
         }
 
-        info!("queue '{}' created", req.name);
-
-        let queue = Arc::new(TaskQueue::new());
-        let worker = Worker::new(
-            queue.clone(),
-            req.output,
-            self.shutdown.clone(),
-            req.max_parallel,
-            req.timeout,
-            req.dir,
-        )?;
-        let worker_handle = WorkerHandle {
-            queue,
-            template: req.template,
-            shutdown: worker.shutdown_notifer(),
-        };
-
-        tokio::spawn(async move {
-            worker.process().await;
-        });
-
+        let worker_handle = self.state.create_queue(req.clone())?;
         map.insert(req.name, worker_handle);
 
         response::ok()
     }
 
     async fn handle_remove_queue(&self, req: request::RemoveQueue) -> Result<response::Empty> {
-        let mut map = self.queues.lock().await;
+        let mut map = self.state.queues.lock().await;
+        if !map.contains_key(&req.name) {
+            bail!("queue '{}' does not exist", &req.name);
+        }
+        self.state
+            .journal
+            .append(&Entry::RemoveQueue(req.clone()))?;
         if let Some(worker) = map.remove(&req.name) {
             worker.shutdown.set();
+            // Removes output of finished tasks, and of the cancelled ones
+            // as they are recorded
+            worker.history.resize(0);
+            for task in worker.queue.collect::<Vec<Task>>().await {
+                worker.history.cancel(task);
+            }
+            for (task, _) in worker.scheduler.collect() {
+                if let Some(task) = worker.scheduler.cancel(task.id) {
+                    worker.history.cancel(task);
+                }
+            }
+            for (_, blocked) in worker.blocked {
+                worker.history.cancel(blocked.task);
+            }
+        }
+        response::ok()
+    }
+
+    async fn handle_update_queue(&self, req: request::UpdateQueue) -> Result<response::Empty> {
+        let mut map = self.state.queues.lock().await;
+        if let Some(worker) = map.get_mut(&req.name) {
+            self.state.update_queue(worker, req)?;
             response::ok()
         } else {
             bail!("queue '{}' does not exist", &req.name);
         }
     }
 
-    async fn handle_send(&self, req: request::Send) -> Result<response::Empty> {
-        let map = self.queues.lock().await;
+    async fn handle_pause_queue(&self, req: request::PauseQueue) -> Result<response::Empty> {
+        let map = self.state.queues.lock().await;
         if let Some(worker) = map.get(&req.name) {
-            let mut args = worker.expand_args(req.args)?;
-            if args.is_empty() {
-                bail!("command cannot be empty");
+            if worker.retiring {
+                bail!("queue '{}' is being removed", &req.name);
             }
-            let binary = args.remove(0);
+            if !*worker.paused.borrow() {
+                self.state.journal.append(&Entry::PauseQueue(req.clone()))?;
+                let _ = worker.paused.send(true);
+                info!("queue '{}' paused", req.name);
+            }
+            response::ok()
+        } else {
+            bail!("queue '{}' does not exist", &req.name);
+        }
+    }
 
-            let task = Task {
-                binary,
-                timeout: req.timeout,
-                dir: req.dir,
-                args,
-            };
-            info!("received task '{}'", task.to_string());
-            worker.queue.push(task).await;
+    async fn handle_resume_queue(&self, req: request::ResumeQueue) -> Result<response::Empty> {
+        self.state.check_accepting()?;
+        let map = self.state.queues.lock().await;
+        if let Some(worker) = map.get(&req.name) {
+            if *worker.paused.borrow() {
+                self.state
+                    .journal
+                    .append(&Entry::ResumeQueue(req.clone()))?;
+                let _ = worker.paused.send(false);
+                info!("queue '{}' resumed", req.name);
+            }
             response::ok()
         } else {
             bail!("queue '{}' does not exist", &req.name);
         }
     }
 
+    async fn handle_send(&mut self, req: request::Send) -> Result<response::Send> {
+        let completions = self.state.completions.subscribe();
+        let ret = if req.stdin != Stdin::Spool {
+            self.state.send(req, None).await
+        } else {
+            // Input has to be in place before the task can be started
+            let id = self.state.next_id.fetch_add(1, Ordering::SeqCst);
+            let ret = match self.receive_stdin(id).await {
+                Ok(()) => self.state.send(req, Some(id)).await,
+                Err(err) => Err(err),
+            };
+            if ret.is_err() {
+                self.state.spool.remove(id);
+            }
+            ret
+        };
+        self.sent = ret.as_ref().ok().map(|resp| (resp.id, completions));
+        ret
+    }
+
+    async fn receive_stdin(&mut self, id: u64) -> Result<()> {
+        let file = self.state.spool.create_stdin(id)?;
+        let mut file = tokio::fs::File::from_std(file);
+        self.connection.read_raw(&mut file).await
+    }
+
+    async fn handle_add_schedule(
+        &self,
+        req: request::AddSchedule,
+    ) -> Result<response::AddSchedule> {
+        self.state.check_accepting()?;
+        let mut map = self.state.queues.lock().await;
+        if let Some(worker) = map.get_mut(&req.name) {
+            if worker.retiring {
+                bail!("queue '{}' is being removed", &req.name);
+            }
+            if worker.expand_args(req.args.clone())?.is_empty() {
+                bail!("command cannot be empty");
+            }
+            let id = self.state.next_schedule_id.fetch_add(1, Ordering::SeqCst);
+            self.state.journal.append(&Entry::AddSchedule {
+                id,
+                schedule: req.clone(),
+            })?;
+            info!(
+                "schedule {} '{}' added to queue '{}'",
+                id, req.when, req.name
+            );
+            let schedule = State::spawn_schedule(&self.state, worker, id, req);
+            worker.schedules.insert(id, schedule);
+            Ok(response::AddSchedule { id })
+        } else {
+            bail!("queue '{}' does not exist", &req.name);
+        }
+    }
+
+    async fn handle_list_schedules(
+        &self,
+        req: request::ListSchedules,
+    ) -> Result<response::ListSchedules> {
+        let map = self.state.queues.lock().await;
+        if let Some(worker) = map.get(&req.name) {
+            let mut schedules = worker
+                .schedules
+                .iter()
+                .map(|(id, schedule)| response::Schedule {
+                    id: *id,
+                    when: schedule.config.when.to_string(),
+                    args: schedule.config.args.clone(),
+                    skip_if_running: schedule.config.skip_if_running,
+                    next: *schedule.next.lock().unwrap(),
+                })
+                .collect::<Vec<_>>();
+            schedules.sort_by_key(|schedule| schedule.id);
+            Ok(response::ListSchedules { schedules })
+        } else {
+            bail!("queue '{}' does not exist", &req.name);
+        }
+    }
+
+    async fn handle_remove_schedule(
+        &self,
+        req: request::RemoveSchedule,
+    ) -> Result<response::Empty> {
+        let mut map = self.state.queues.lock().await;
+        for worker in map.values_mut() {
+            if let Some(schedule) = worker.schedules.get(&req.id) {
+                if schedule.configured {
+                    bail!("schedule {} is defined in the configuration file", req.id);
+                }
+                let schedule = worker.schedules.remove(&req.id).unwrap();
+                schedule.cancel.set();
+                self.state
+                    .journal
+                    .append(&Entry::RemoveSchedule { id: req.id })?;
+                info!("schedule {} removed", req.id);
+                return response::ok();
+            }
+        }
+        bail!("schedule {} does not exist", req.id);
+    }
+
     async fn handle_list_queues(&self) -> Result<response::ListQueues> {
-        let queues = self
-            .queues
-            .lock()
-            .await
-            .keys()
-            .cloned()
-            .map(|name| response::Queue { name })
-            .collect();
+        let map = self.state.queues.lock().await;
+        let mut queues = vec![];
+        for worker in map.values() {
+            queues.push(worker.describe().await);
+        }
+        queues.sort_by(|a, b| a.name.cmp(&b.name));
         Ok(response::ListQueues { queues })
     }
 
+    async fn handle_show_queue(&self, req: request::ShowQueue) -> Result<response::Queue> {
+        let map = self.state.queues.lock().await;
+        match map.get(&req.name) {
+            Some(worker) => Ok(worker.describe().await),
+            None => bail!("queue '{}' does not exist", &req.name),
+        }
+    }
+
     async fn handle_list_tasks(&self, req: request::ListTasks) -> Result<response::ListTasks> {
-        let map = self.queues.lock().await;
+        let map = self.state.queues.lock().await;
         if let Some(worker) = map.get(&req.name) {
-            let tasks = worker
-                .queue
-                .collect::<Vec<Task>>()
-                .await
-                .into_iter()
-                .map(|task| response::Task { args: task.args })
+            let mut tasks = worker
+                .running
+                .lock()
+                .unwrap()
+                .values()
+                .map(|running| response::Task {
+                    id: running.task.id,
+                    args: running.task.command(),
+                    state: TaskState::Running,
+                    priority: running.task.priority,
+                    attempt: running.task.attempts + 1,
+                    pid: running.pid,
+                    scheduled: None,
+                    started: Some(running.started),
+                    elapsed: running.started.elapsed().ok(),
+                })
+                .collect::<Vec<_>>();
+            tasks.sort_by_key(|task| task.id);
+            tasks.extend(
+                worker
+                    .queue
+                    .collect::<Vec<Task>>()
+                    .await
+                    .into_iter()
+                    .map(|task| response::Task {
+                        id: task.id,
+                        args: task.command(),
+                        state: TaskState::Queued,
+                        priority: task.priority,
+                        attempt: task.attempts + 1,
+                        pid: None,
+                        scheduled: None,
+                        started: None,
+                        elapsed: None,
+                    }),
+            );
+            tasks.extend(
+                worker
+                    .scheduler
+                    .collect()
+                    .into_iter()
+                    .map(|(task, at)| response::Task {
+                        id: task.id,
+                        args: task.command(),
+                        state: TaskState::Scheduled,
+                        priority: task.priority,
+                        attempt: task.attempts + 1,
+                        pid: None,
+                        scheduled: Some(at),
+                        started: None,
+                        elapsed: None,
+                    }),
+            );
+            let mut blocked: Vec<&Task> = worker
+                .blocked
+                .values()
+                .map(|blocked| &blocked.task)
                 .collect();
+            blocked.sort_by_key(|task| task.id);
+            tasks.extend(blocked.into_iter().map(|task| response::Task {
+                id: task.id,
+                args: task.command(),
+                state: TaskState::Blocked,
+                priority: task.priority,
+                attempt: task.attempts + 1,
+                pid: None,
+                scheduled: task.at,
+                started: None,
+                elapsed: None,
+            }));
             Ok(response::ListTasks { tasks })
         } else {
             bail!("queue '{}' does not exist", &req.name);
         }
     }
+
+    async fn handle_history(&self, req: request::History) -> Result<response::History> {
+        let map = self.state.queues.lock().await;
+        if let Some(worker) = map.get(&req.name) {
+            let tasks = worker
+                .history
+                .collect::<Vec<Finished>>()
+                .iter()
+                .map(response::FinishedTask::from)
+                .collect();
+            Ok(response::History { tasks })
+        } else {
+            bail!("queue '{}' does not exist", &req.name);
+        }
+    }
+
+    async fn handle_cancel_task(&self, req: request::CancelTask) -> Result<response::Empty> {
+        let mut map = self.state.queues.lock().await;
+        for worker in map.values_mut() {
+            let task = match worker.queue.remove(|task| task.id == req.id).await {
+                Some(task) => Some(task),
+                None => worker
+                    .scheduler
+                    .cancel(req.id)
+                    .or_else(|| worker.blocked.remove(&req.id).map(|blocked| blocked.task)),
+            };
+            if let Some(task) = task {
+                info!("task {} cancelled", req.id);
+                worker.history.cancel(task);
+                return response::ok();
+            }
+            if let Some(running) = worker.running.lock().unwrap().get(&req.id) {
+                running.cancel.set();
+                return response::ok();
+            }
+        }
+        bail!("task {} is not queued or running", req.id);
+    }
+
+    async fn handle_logs(&mut self, req: request::Logs) -> Result<()> {
+        let files = match self.open_logs(req.id).await {
+            Ok(files) => files,
+            Err(err) => {
+                let resp = Response::<response::Empty>::Error(err.into());
+                return self.connection.write_message(&resp).await;
+            }
+        };
+        let resp = Response::Success(response::Empty {});
+        self.connection.write_message(&resp).await?;
+        for file in files {
+            match file {
+                Some(mut file) => self.connection.write_raw(&mut file).await?,
+                None => self.connection.write_raw(&mut tokio::io::empty()).await?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn open_logs(&self, id: u64) -> Result<[Option<tokio::fs::File>; 2]> {
+        let stdout = self.state.spool.open(id, Stream::Stdout).await?;
+        let stderr = self.state.spool.open(id, Stream::Stderr).await?;
+        if stdout.is_none() && stderr.is_none() && !self.state.is_pending(id).await {
+            bail!("output of task {} is not available", id);
+        }
+        Ok([stdout, stderr])
+    }
+
+    async fn handle_follow_logs(&mut self, req: request::FollowLogs) -> Result<()> {
+        let (stdout, stderr, receiver) = match self.follow_logs(req).await {
+            Ok(ret) => ret,
+            Err(err) => {
+                let resp = Response::<response::Output>::Error(err.into());
+                return self.connection.write_message(&resp).await;
+            }
+        };
+        for (stream, data) in [(Stream::Stdout, stdout), (Stream::Stderr, stderr)] {
+            if !data.is_empty() {
+                self.write_output(response::Output::Data { stream, data })
+                    .await?;
+            }
+        }
+        if let Some(mut receiver) = receiver {
+            loop {
+                match receiver.recv().await {
+                    Ok((stream, mut data)) => {
+                        data.push('\n');
+                        self.write_output(response::Output::Data { stream, data })
+                            .await?;
+                    }
+                    Err(RecvError::Lagged(count)) => {
+                        warn!("follower lagged behind, {} lines skipped", count);
+                    }
+                    Err(RecvError::Closed) => break,
+                }
+            }
+        }
+        self.write_output(response::Output::End).await
+    }
+
+    async fn follow_logs(
+        &self,
+        req: request::FollowLogs,
+    ) -> Result<(String, String, Option<Lines>)> {
+        let mut starts = self.state.starts.subscribe();
+        let mut completions = self.state.completions.subscribe();
+        loop {
+            if let Some(capture) = self.state.find_capture(req.id).await {
+                let (stdout, stderr, receiver) = capture.follow().await?;
+                return Ok((stdout, stderr, Some(receiver)));
+            }
+            if !self.state.is_pending(req.id).await {
+                break;
+            }
+            // Queued, delayed and blocked tasks are followed once they start,
+            // the task is looked up again if it is cancelled meanwhile
+            loop {
+                tokio::select! {
+                    started = starts.recv() => match started {
+                        Ok(id) if id != req.id => {}
+                        Err(RecvError::Closed) => bail!("server is shutting down"),
+                        _ => break,
+                    },
+                    finished = completions.recv() => match finished {
+                        Ok(finished) if finished.task.id != req.id => {}
+                        Err(RecvError::Closed) => bail!("server is shutting down"),
+                        _ => break,
+                    },
+                }
+            }
+        }
+        // Output of finished tasks cannot change
+        self.open_logs(req.id).await?;
+        let spool = &self.state.spool;
+        let stdout = spool.read_string(req.id, Stream::Stdout).await?;
+        let stderr = spool.read_string(req.id, Stream::Stderr).await?;
+        Ok((stdout, stderr, None))
+    }
+
+    async fn write_output(&mut self, output: response::Output) -> Result<()> {
+        let resp = Response::Success(output);
+        self.connection.write_message(&resp).await
+    }
+
+    async fn handle_wait_task(&mut self, req: request::WaitTask) -> Result<response::Wait> {
+        if let Some((id, completions)) = self.sent.take() {
+            // The task was sent on this connection, it might have finished
+            // already but its completion cannot have been missed
+            if id == req.id {
+                let pending = std::iter::once(id).collect();
+                return self.state.wait_for(completions, pending, vec![]).await;
+            }
+        }
+        let completions = self.state.completions.subscribe();
+        let mut finished = self.state.find_finished(req.id).await;
+        if finished.is_none() && !self.state.is_pending(req.id).await {
+            // The task might have finished after we looked at the history
+            finished = self.state.find_finished(req.id).await;
+            if finished.is_none() {
+                return self.state.journaled_wait(req.id);
+            }
+        }
+        match finished {
+            Some(finished) => {
+                self.state
+                    .wait_for(completions, HashSet::new(), vec![finished])
+                    .await
+            }
+            None => {
+                let pending = std::iter::once(req.id).collect();
+                self.state.wait_for(completions, pending, vec![]).await
+            }
+        }
+    }
+
+    async fn handle_wait_queue(&self, req: request::WaitQueue) -> Result<response::Wait> {
+        let completions = self.state.completions.subscribe();
+        let pending = {
+            let map = self.state.queues.lock().await;
+            if let Some(worker) = map.get(&req.name) {
+                let mut pending: HashSet<u64> = worker
+                    .queue
+                    .collect::<Vec<Task>>()
+                    .await
+                    .iter()
+                    .map(|task| task.id)
+                    .collect();
+                pending.extend(worker.running.lock().unwrap().keys());
+                pending.extend(worker.scheduler.collect().iter().map(|(task, _)| task.id));
+                pending.extend(worker.blocked.keys());
+                pending
+            } else {
+                bail!("queue '{}' does not exist", &req.name);
+            }
+        };
+        self.state.wait_for(completions, pending, vec![]).await
+    }
 }
 
 pub struct QueueServer {
     listener: UnixListener,
-    queues: QueueMap,
+    state: Arc<State>,
     shutdown: Trigger,
     shutdown_waiter: DropWaiter,
+    processes: DropWaiter,
 }
 
 impl QueueServer {
-    pub fn new(listener: UnixListener) -> Result<Self> {
-        let queues = Arc::new(Mutex::new(HashMap::new()));
+    pub async fn new<P>(listener: UnixListener, state_dir: P, config: Config) -> Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let (journal, entries) = Journal::open(&state_dir)?;
+        let next_id = journal::next_id(&entries);
+        let next_schedule_id = journal::next_schedule_id(&entries);
+        let outcomes = journal::outcomes(&entries);
+        let mut restored = journal::replay(entries);
+
+        // Configured settings replace the journaled ones, schedules of the
+        // configuration are not journaled as they are added on every start
+        let mut configured: HashMap<String, Vec<request::AddSchedule>> = HashMap::new();
+        for queue in config.queues {
+            let name = queue.queue.name.clone();
+            match restored.iter_mut().find(|state| state.config.name == name) {
+                Some(state) => {
+                    state.config = queue.queue;
+                    state.configured = true;
+                }
+                None => restored.push(QueueState {
+                    config: queue.queue,
+                    paused: false,
+                    configured: true,
+                    tasks: vec![],
+                    schedules: vec![],
+                    history: vec![],
+                }),
+            }
+            configured.insert(name, queue.schedules);
+        }
+        // Queues removed from the file while the server was stopped
+        let retired: Vec<String> = match config.path {
+            Some(_) => restored
+                .iter()
+                .filter(|queue| queue.configured && !configured.contains_key(&queue.config.name))
+                .map(|queue| queue.config.name.clone())
+                .collect(),
+            None => vec![],
+        };
+        journal.rewrite(&journal::compact(
+            &restored,
+            &outcomes,
+            next_id,
+            next_schedule_id,
+        ))?;
+
+        // Output is kept for pending tasks and tasks remaining in history
+        let spool = Spool::new(state_dir.as_ref().join(SPOOL_DIR))?;
+        spool.retain(
+            &restored
+                .iter()
+                .flat_map(|queue| {
+                    let pending = queue.tasks.iter().map(|task| task.id);
+                    pending.chain(queue.history.iter().map(|finished| finished.task.id))
+                })
+                .collect(),
+        )?;
+
         let (shutdown, _) = condition();
+        let (completions, _) = broadcast::channel(COMPLETIONS_CAPACITY);
+        let (starts, _) = broadcast::channel(COMPLETIONS_CAPACITY);
+        let processes = DropWaiter::new();
+        let state = Arc::new(State {
+            queues: Mutex::new(HashMap::new()),
+            journal: Arc::new(journal),
+            next_id: AtomicU64::new(next_id),
+            next_schedule_id: AtomicU64::new(next_schedule_id),
+            completions,
+            starts,
+            spool: Arc::new(spool),
+            outcomes: std::sync::Mutex::new(outcomes),
+            processes: std::sync::Mutex::new(Some(processes.guard())),
+            config: config.path,
+            draining: AtomicBool::new(false),
+            shutdown: shutdown.clone(),
+        });
+
+        let completions = state.completions.subscribe();
+        let resolver = state.clone();
+        tokio::spawn(async move { resolver.resolve_blocked(completions).await });
+
+        {
+            let mut map = state.queues.lock().await;
+            let mut deferred = vec![];
+            for queue in restored {
+                let name = queue.config.name.clone();
+                let mut worker_handle = match state.spawn_worker(queue.config, queue.paused) {
+                    Ok(worker_handle) => worker_handle,
+                    Err(err) => {
+                        error!("restoring queue '{}' failed: {}", name, err);
+                        continue;
+                    }
+                };
+                info!(
+                    "queue '{}' restored with {} pending tasks",
+                    name,
+                    queue.tasks.len()
+                );
+                worker_handle.history.restore(queue.history);
+                for task in queue.tasks {
+                    if task.after.is_empty() {
+                        worker_handle.submit(task).await;
+                    } else {
+                        deferred.push((name.clone(), task));
+                    }
+                }
+                for (id, config) in queue.schedules {
+                    let schedule = State::spawn_schedule(&state, &worker_handle, id, config);
+                    worker_handle.schedules.insert(id, schedule);
+                }
+                worker_handle.configured = queue.configured;
+                if let Some(schedules) = configured.remove(&name) {
+                    State::add_configured_schedules(&state, &mut worker_handle, schedules);
+                }
+                map.insert(name, worker_handle);
+            }
+
+            // Dependencies can only be resolved once all queues are restored
+            deferred.sort_by_key(|(_, task)| task.id);
+            for (name, task) in deferred {
+                let lookups = State::lookups(&map);
+                let resolution = state.resolve_dependencies(&lookups, &task).await;
+                let worker_handle = map.get_mut(&name).unwrap();
+                match resolution {
+                    Resolution::Ready => worker_handle.start(task, vec![]).await,
+                    Resolution::Waiting(waiting) => worker_handle.start(task, waiting).await,
+                    Resolution::Failed(dependency, _) => {
+                        warn!(
+                            "task {} cancelled, dependency {} failed",
+                            task.id, dependency
+                        );
+                        state.cancel_blocked(worker_handle, task);
+                    }
+                }
+            }
+
+            for name in retired {
+                if let Some(worker_handle) = map.get_mut(&name) {
+                    State::retire(&state, worker_handle);
+                }
+            }
+        }
+
         let shutdown_waiter = DropWaiter::new();
         Ok(Self {
             listener,
-            queues,
+            state,
             shutdown,
             shutdown_waiter,
+            processes,
         })
     }
 
@@ -219,6 +1768,19 @@ impl QueueServer {
         }
     }
 
+    pub fn reload_notifier(&self) -> impl Fn() + Send + 'static {
+        let state = self.state.clone();
+        move || {
+            let state = state.clone();
+            tokio::spawn(async move {
+                info!("reload requested");
+                if let Err(err) = State::reload(&state).await {
+                    error!("reloading configuration failed: {}", err);
+                }
+            });
+        }
+    }
+
     pub async fn serve(mut self) -> Result<()> {
         let mut shutdown = self.shutdown.waiter();
 
@@ -228,17 +1790,19 @@ impl QueueServer {
         };
 
         self.shutdown_waiter.wait().await;
+        // Tasks are given their grace period to stop
+        self.state.processes.lock().unwrap().take();
+        self.processes.wait().await;
         ret
     }
 
     async fn serve_inner(&mut self) -> Result<()> {
         loop {
             let (connection, _) = self.listener.accept().await?;
-            let queues = self.queues.clone();
-            let shutdown = self.shutdown.clone();
+            let state = self.state.clone();
             let sentinel = self.shutdown_waiter.guard();
             tokio::spawn(async move {
-                let mut client = ClientHandler::new(connection, queues, shutdown, sentinel);
+                let mut client = ClientHandler::new(connection, state, sentinel);
                 if let Err(err) = client.serve().await {
                     error!("client error: {}", err);
                 }
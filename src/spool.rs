@@ -0,0 +1,173 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{debug, warn};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{broadcast, Mutex};
+
+use crate::response::Stream;
+use crate::utils;
+
+const FOLLOW_CAPACITY: usize = 1024;
+
+pub type Lines = broadcast::Receiver<(Stream, String)>;
+
+/// Directory holding the captured output of each task.
+pub struct Spool {
+    dir: PathBuf,
+}
+
+impl Spool {
+    pub fn new<P>(dir: P) -> io::Result<Self>
+    where
+        P: AsRef<Path>,
+    {
+        let dir = dir.as_ref().to_path_buf();
+        utils::create_private_dir(&dir)?;
+        Ok(Self { dir })
+    }
+
+    pub fn path(&self, id: u64, stream: Stream) -> PathBuf {
+        self.dir.join(format!("{}.{}", id, stream))
+    }
+
+    /// Opens the output file of a task, output of previous attempts is kept
+    /// unless `truncate` is set.
+    pub fn create(&self, id: u64, stream: Stream, truncate: bool) -> io::Result<File> {
+        OpenOptions::new()
+            .append(!truncate)
+            .write(true)
+            .truncate(truncate)
+            .create(true)
+            .mode(0o600)
+            .open(self.path(id, stream))
+    }
+
+    pub fn stdin_path(&self, id: u64) -> PathBuf {
+        self.dir.join(format!("{}.stdin", id))
+    }
+
+    pub fn create_stdin(&self, id: u64) -> io::Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(self.stdin_path(id))
+    }
+
+    pub async fn open(&self, id: u64, stream: Stream) -> io::Result<Option<tokio::fs::File>> {
+        match tokio::fs::File::open(self.path(id, stream)).await {
+            Ok(file) => Ok(Some(file)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Reads the output of a task, invalid UTF-8 is replaced.
+    pub async fn read_string(&self, id: u64, stream: Stream) -> io::Result<String> {
+        match tokio::fs::read(self.path(id, stream)).await {
+            Ok(data) => Ok(String::from_utf8_lossy(&data).into_owned()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn remove(&self, id: u64) {
+        let paths = [
+            self.path(id, Stream::Stdout),
+            self.path(id, Stream::Stderr),
+            self.stdin_path(id),
+        ];
+        for path in paths {
+            match fs::remove_file(&path) {
+                Ok(()) => debug!("removed '{}'", path.display()),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => warn!("removing '{}' failed: {}", path.display(), err),
+            }
+        }
+    }
+
+    /// Removes output of all tasks except the given ones.
+    pub fn retain(&self, ids: &HashSet<u64>) -> io::Result<()> {
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            let id = path
+                .file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse().ok());
+            match id {
+                Some(id) if ids.contains(&id) => {}
+                _ => {
+                    if let Err(err) = fs::remove_file(&path) {
+                        warn!("removing '{}' failed: {}", path.display(), err);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Output of a running task shared by its spool writers and by clients
+/// following the output.
+pub struct Capture {
+    id: u64,
+    spool: Arc<Spool>,
+    followers: Mutex<broadcast::Sender<(Stream, String)>>,
+}
+
+impl Capture {
+    pub fn new(id: u64, spool: Arc<Spool>) -> Self {
+        let (followers, _) = broadcast::channel(FOLLOW_CAPACITY);
+        Self {
+            id,
+            spool,
+            followers: Mutex::new(followers),
+        }
+    }
+
+    pub fn writer(self: &Arc<Self>, stream: Stream, truncate: bool) -> io::Result<CaptureWriter> {
+        let file = self.spool.create(self.id, stream, truncate)?;
+        Ok(CaptureWriter {
+            stream,
+            file: tokio::fs::File::from_std(file),
+            capture: self.clone(),
+        })
+    }
+
+    /// Returns the output captured so far and a receiver of the following
+    /// lines. The receiver is closed when all writers are dropped.
+    pub async fn follow(&self) -> io::Result<(String, String, Lines)> {
+        let followers = self.followers.lock().await;
+        Ok((
+            self.spool.read_string(self.id, Stream::Stdout).await?,
+            self.spool.read_string(self.id, Stream::Stderr).await?,
+            followers.subscribe(),
+        ))
+    }
+}
+
+pub struct CaptureWriter {
+    stream: Stream,
+    file: tokio::fs::File,
+    capture: Arc<Capture>,
+}
+
+impl CaptureWriter {
+    pub async fn write_line(&mut self, line: &[u8]) -> io::Result<()> {
+        // Followers must see each line either in the file or in the channel
+        let followers = self.capture.followers.lock().await;
+        self.file.write_all(line).await?;
+        self.file.write_all(b"\n").await?;
+        // Waits for the data to reach the file
+        self.file.flush().await?;
+        let line = String::from_utf8_lossy(line).into_owned();
+        let _ = followers.send((self.stream, line));
+        Ok(())
+    }
+}
@@ -1,5 +1,6 @@
+use std::sync::Mutex;
 use tokio::sync::broadcast::{self, Receiver, Sender};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Semaphore};
 
 #[derive(Clone)]
 pub struct Trigger(Sender<()>);
@@ -42,7 +43,7 @@ pub fn condition() -> (Trigger, Waiter) {
 }
 
 #[derive(Clone)]
-pub struct DropGuard(mpsc::Sender<()>);
+pub struct DropGuard(#[allow(dead_code)] mpsc::Sender<()>);
 
 pub struct DropWaiter {
     sender: mpsc::Sender<()>,
@@ -64,3 +65,73 @@ impl DropWaiter {
         self.receiver.recv().await;
     }
 }
+
+/// Semaphore whose number of permits can be changed while some of them are
+/// acquired.
+pub struct Limit {
+    semaphore: Semaphore,
+    state: Mutex<LimitState>,
+}
+
+struct LimitState {
+    limit: usize,
+    // Permits that are acquired but should not be released
+    excess: usize,
+}
+
+impl Limit {
+    pub fn new(limit: usize) -> Self {
+        Self {
+            semaphore: Semaphore::new(limit),
+            state: Mutex::new(LimitState { limit, excess: 0 }),
+        }
+    }
+
+    pub async fn acquire(&self) {
+        self.semaphore.acquire().await.unwrap().forget();
+    }
+
+    pub fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.excess > 0 {
+            state.excess -= 1;
+        } else {
+            self.semaphore.add_permits(1);
+        }
+    }
+
+    pub fn set(&self, limit: usize) {
+        let mut state = self.state.lock().unwrap();
+        if limit > state.limit {
+            let added = limit - state.limit;
+            let cancelled = added.min(state.excess);
+            state.excess -= cancelled;
+            self.semaphore.add_permits(added - cancelled);
+        } else {
+            let mut removed = state.limit - limit;
+            while removed > 0 {
+                match self.semaphore.try_acquire() {
+                    Ok(permit) => permit.forget(),
+                    Err(_) => break,
+                }
+                removed -= 1;
+            }
+            state.excess += removed;
+        }
+        state.limit = limit;
+    }
+}
+
+#[test]
+fn test_limit() {
+    let limit = Limit::new(2);
+    assert!(limit.semaphore.try_acquire().map(|p| p.forget()).is_ok());
+    limit.set(1);
+    assert_eq!(limit.semaphore.available_permits(), 0);
+    limit.set(0);
+    assert_eq!(limit.semaphore.available_permits(), 0);
+    limit.release();
+    assert_eq!(limit.semaphore.available_permits(), 0);
+    limit.set(3);
+    assert_eq!(limit.semaphore.available_permits(), 3);
+}
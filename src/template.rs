@@ -1,5 +1,5 @@
+use std::fmt;
 use std::str::FromStr;
-use std::string::ToString;
 
 use anyhow::{anyhow, Error, Result};
 use serde::de::{self, Deserialize, Deserializer};
@@ -8,14 +8,14 @@ use serde::ser::{Serialize, Serializer};
 const ARG_STR: &str = "{}";
 const VAR_ARG_STR: &str = "{...}";
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 enum Piece {
     Static(String),
     Arg,
     VarArg,
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 enum SizeInfo {
     Exactly {
         arg_count: usize,
@@ -26,7 +26,7 @@ enum SizeInfo {
     },
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub struct Template {
     pieces: Vec<Piece>,
     size_info: SizeInfo,
@@ -146,17 +146,18 @@ impl<'de> Deserialize<'de> for Template {
     }
 }
 
-impl ToString for Template {
-    fn to_string(&self) -> String {
-        self.pieces
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let pieces = self
+            .pieces
             .iter()
             .map(|piece| match piece {
                 Piece::Arg => ARG_STR.into(),
                 Piece::VarArg => VAR_ARG_STR.into(),
                 Piece::Static(s) => shlex::quote(s),
             })
-            .collect::<Vec<_>>()
-            .join(" ")
+            .collect::<Vec<_>>();
+        write!(f, "{}", pieces.join(" "))
     }
 }
 
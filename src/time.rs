@@ -0,0 +1,172 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::ffi;
+
+pub fn format_time(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0);
+    match ffi::localtime(secs as libc::time_t) {
+        Ok(tm) => format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec
+        ),
+        Err(_) => secs.to_string(),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct LocalTime {
+    date: Option<(i32, i32, i32)>,
+    hour: i32,
+    minute: i32,
+    second: i32,
+}
+
+fn parse_numbers(input: &str, separator: char) -> Option<Vec<i32>> {
+    input
+        .split(separator)
+        .map(|number| {
+            if number.is_empty() || !number.chars().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            number.parse().ok()
+        })
+        .collect()
+}
+
+fn parse_local_time(input: &str) -> Option<LocalTime> {
+    let mut time = LocalTime {
+        date: None,
+        hour: 0,
+        minute: 0,
+        second: 0,
+    };
+    for part in input.trim().split(&[' ', 'T'][..]) {
+        if part.contains('-') && time.date.is_none() {
+            match parse_numbers(part, '-')?[..] {
+                [year, month, day] if (1..=12).contains(&month) && (1..=31).contains(&day) => {
+                    time.date = Some((year, month, day))
+                }
+                _ => return None,
+            }
+        } else if part.contains(':') {
+            match parse_numbers(part, ':')?[..] {
+                [hour, minute] => {
+                    time.hour = hour;
+                    time.minute = minute;
+                }
+                [hour, minute, second] => {
+                    time.hour = hour;
+                    time.minute = minute;
+                    time.second = second;
+                }
+                _ => return None,
+            }
+            if time.hour > 23 || time.minute > 59 || time.second > 59 {
+                return None;
+            }
+        } else if !part.is_empty() {
+            return None;
+        }
+    }
+    if time.date.is_none() && !input.contains(':') {
+        return None;
+    }
+    Some(time)
+}
+
+fn mktime(time: &LocalTime, mut tm: libc::tm, add_days: i32) -> Result<libc::time_t> {
+    if let Some((year, month, day)) = time.date {
+        tm.tm_year = year - 1900;
+        tm.tm_mon = month - 1;
+        tm.tm_mday = day;
+    }
+    tm.tm_mday += add_days;
+    tm.tm_hour = time.hour;
+    tm.tm_min = time.minute;
+    tm.tm_sec = time.second;
+    tm.tm_isdst = -1;
+    Ok(ffi::mktime(&mut tm)?)
+}
+
+/// Checks that `secs` is the given local time. mktime normalises values that
+/// are out of range, such as February 31st or times skipped by a change to
+/// daylight saving time.
+fn exists(time: &LocalTime, secs: libc::time_t) -> Result<bool> {
+    let tm = ffi::localtime(secs)?;
+    if let Some(date) = time.date {
+        if (tm.tm_year + 1900, tm.tm_mon + 1, tm.tm_mday) != date {
+            return Ok(false);
+        }
+    }
+    Ok((tm.tm_hour, tm.tm_min, tm.tm_sec) == (time.hour, time.minute, time.second))
+}
+
+/// Parses local time in `[YYYY-MM-DD] [HH:MM[:SS]]` format. Time without date
+/// refers to the next occurrence of that time.
+pub fn parse_time(input: &str) -> Result<SystemTime> {
+    let time = parse_local_time(input).ok_or_else(|| anyhow!("invalid time '{}'", input))?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0) as libc::time_t;
+    let tm = ffi::localtime(now)?;
+    let mut secs = mktime(&time, tm, 0)?;
+    if time.date.is_none() && secs <= now {
+        secs = mktime(&time, tm, 1)?;
+    }
+    if !exists(&time, secs)? {
+        bail!("time '{}' does not exist", input.trim());
+    }
+    Ok(UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64))
+}
+
+#[test]
+fn test_parse_local_time() {
+    let time = |date, hour, minute, second| {
+        Some(LocalTime {
+            date,
+            hour,
+            minute,
+            second,
+        })
+    };
+    assert_eq!(parse_local_time("12:30"), time(None, 12, 30, 0));
+    assert_eq!(parse_local_time("08:05:09"), time(None, 8, 5, 9));
+    assert_eq!(
+        parse_local_time("2021-03-04"),
+        time(Some((2021, 3, 4)), 0, 0, 0)
+    );
+    assert_eq!(
+        parse_local_time("2021-03-04 23:59"),
+        time(Some((2021, 3, 4)), 23, 59, 0)
+    );
+    assert_eq!(
+        parse_local_time("2021-03-04T01:02:03"),
+        time(Some((2021, 3, 4)), 1, 2, 3)
+    );
+    assert_eq!(parse_local_time("24:00"), None);
+    assert_eq!(parse_local_time("12"), None);
+    assert_eq!(parse_local_time("2021-13-01"), None);
+    assert_eq!(parse_local_time("12:30 tomorrow"), None);
+    assert_eq!(parse_local_time("+12:30"), None);
+}
+
+#[test]
+fn test_parse_time() {
+    ffi::use_utc();
+    assert!(parse_time("2021-02-28 12:00").is_ok());
+    assert!(parse_time("2020-02-29").is_ok());
+    assert!(parse_time("2021-02-29").is_err());
+    assert!(parse_time("2021-02-31 12:00").is_err());
+    assert!(parse_time("2021-04-31").is_err());
+}
@@ -1,8 +1,11 @@
+use std::fs;
 use std::io;
-use std::path::PathBuf;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
 use log::SetLoggerError;
 use simplelog::{LevelFilter, WriteLogger};
+use tokio::signal::unix::{signal, SignalKind};
 
 #[macro_export]
 macro_rules! impl_trivial_from {
@@ -28,15 +31,45 @@ pub fn get_runtime() -> io::Result<tokio::runtime::Runtime> {
         .build()
 }
 
-pub fn spawn_signal_handler<F>(notify: F)
+pub fn spawn_signal_handler<F>(notify: F) -> io::Result<()>
 where
     F: Fn() + Send + 'static,
 {
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut quit = signal(SignalKind::quit())?;
     tokio::spawn(async move {
-        if tokio::signal::ctrl_c().await.is_ok() {
+        tokio::select! {
+            _ = interrupt.recv() => {},
+            _ = terminate.recv() => {},
+            _ = quit.recv() => {},
+        }
+        notify();
+    });
+    Ok(())
+}
+
+pub fn spawn_reload_handler<F>(notify: F) -> io::Result<()>
+where
+    F: Fn() + Send + 'static,
+{
+    let mut hangup = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangup.recv().await.is_some() {
             notify();
         }
     });
+    Ok(())
+}
+
+/// Creates a directory accessible only by the current user, permissions of an
+/// existing directory are restricted as well.
+pub fn create_private_dir(path: &Path) -> io::Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))
 }
 
 pub struct FileRemover(PathBuf);
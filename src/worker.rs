@@ -1,69 +1,354 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
 use std::io;
+use std::iter::FromIterator;
+use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
-use std::string::ToString;
-use std::sync::Arc;
-use std::time::Duration;
+use std::process::{ExitStatus, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
 
-use log::{debug, error};
-use tokio::sync::Semaphore;
+use anyhow::Context;
+use log::{debug, error, info};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, watch};
 
+use crate::duration::format_duration;
+use crate::env::Environment;
+use crate::journal::{Entry, Journal};
+use crate::kill::KillPolicy;
 use crate::output::Output;
-use crate::process::Process;
-use crate::queue::Queue;
+use crate::process::{Outcome, Process};
+use crate::queue::{Priority, Queue};
+use crate::request::{Dependency, Stdin};
+use crate::response::{self, Stream, TaskState};
+use crate::retry::RetryPolicy;
+use crate::scheduler::Scheduler;
+use crate::spool::{Capture, CaptureWriter, Spool};
 use crate::sync;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Task {
+    pub id: u64,
     pub binary: String,
     pub timeout: Option<Duration>,
     pub dir: Option<PathBuf>,
+    #[serde(default)]
+    pub cwd: Option<PathBuf>,
     pub args: Vec<String>,
+    #[serde(default)]
+    pub priority: i32,
+    #[serde(default)]
+    pub at: Option<SystemTime>,
+    #[serde(default)]
+    pub after: Vec<Dependency>,
+    #[serde(default)]
+    pub stdin: Stdin,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+    #[serde(default)]
+    pub kill: KillPolicy,
+    #[serde(default)]
+    pub env: Environment,
+    #[serde(default)]
+    pub attempts: u32,
 }
 
-impl ToString for Task {
-    fn to_string(&self) -> String {
+impl Task {
+    pub fn command(&self) -> Vec<String> {
         std::iter::once(&self.binary)
+            .chain(self.args.iter())
+            .cloned()
+            .collect()
+    }
+}
+
+impl Priority for Task {
+    fn priority(&self) -> i32 {
+        self.priority
+    }
+}
+
+impl fmt::Display for Task {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let command = std::iter::once(&self.binary)
             .chain(self.args.iter())
             .map(|s| shlex::quote(s))
-            .collect::<Vec<_>>()
-            .join(" ")
+            .collect::<Vec<_>>();
+        write!(f, "{}", command.join(" "))
     }
 }
 
 pub type TaskQueue = Queue<Task>;
 
+pub struct Running {
+    pub task: Task,
+    pub pid: Option<u32>,
+    pub started: SystemTime,
+    pub cancel: sync::Trigger,
+    pub capture: Arc<Capture>,
+}
+
+pub type RunningTasks = Arc<Mutex<HashMap<u64, Running>>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Finished {
+    pub task: Task,
+    pub state: TaskState,
+    #[serde(with = "exit_status")]
+    pub status: Option<ExitStatus>,
+    pub started: Option<SystemTime>,
+    pub ended: SystemTime,
+}
+
+impl From<&Finished> for response::FinishedTask {
+    fn from(finished: &Finished) -> Self {
+        response::FinishedTask {
+            id: finished.task.id,
+            args: finished.task.command(),
+            state: finished.state,
+            attempts: finished.task.attempts,
+            exit_code: finished.status.and_then(|status| status.code()),
+            signal: finished.status.and_then(|status| status.signal()),
+            started: finished.started,
+            ended: finished.ended,
+        }
+    }
+}
+
+pub type Completions = broadcast::Sender<Finished>;
+/// IDs of tasks as they are started.
+pub type Starts = broadcast::Sender<u64>;
+
+/// Totals of finished tasks, unlike the history these are not limited in
+/// size.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Statistics {
+    pub completed: u64,
+    pub failed: u64,
+    pub runtime: Duration,
+}
+
+impl Statistics {
+    fn add(&mut self, finished: &Finished) {
+        match finished.state {
+            TaskState::Finished => self.completed += 1,
+            TaskState::Failed | TaskState::TimedOut => self.failed += 1,
+            _ => return,
+        }
+        if let Some(started) = finished.started {
+            self.runtime += finished.ended.duration_since(started).unwrap_or_default();
+        }
+    }
+
+    pub fn average_runtime(&self) -> Option<Duration> {
+        match self.completed + self.failed {
+            0 => None,
+            count => Some(Duration::from_secs_f64(
+                self.runtime.as_secs_f64() / count as f64,
+            )),
+        }
+    }
+}
+
+pub struct History {
+    name: String,
+    finished: Mutex<VecDeque<Finished>>,
+    statistics: Mutex<Statistics>,
+    size: AtomicUsize,
+    completions: Completions,
+    journal: Arc<Journal>,
+    spool: Arc<Spool>,
+}
+
+impl History {
+    pub fn new(
+        name: String,
+        size: usize,
+        completions: Completions,
+        journal: Arc<Journal>,
+        spool: Arc<Spool>,
+    ) -> Self {
+        Self {
+            name,
+            finished: Mutex::new(VecDeque::new()),
+            statistics: Mutex::new(Statistics::default()),
+            size: AtomicUsize::new(size),
+            completions,
+            journal,
+            spool,
+        }
+    }
+
+    /// Adds tasks that finished before the server was restarted.
+    pub fn restore(&self, finished: Vec<Finished>) {
+        let mut statistics = self.statistics.lock().unwrap();
+        for finished in finished.iter() {
+            statistics.add(finished);
+        }
+        let mut history = self.finished.lock().unwrap();
+        history.extend(finished);
+        self.trim(&mut history);
+    }
+
+    /// Records a finished task in the journal and in the history.
+    pub fn record(&self, finished: Finished) {
+        let entry = Entry::Finished {
+            queue: self.name.clone(),
+            finished: finished.clone(),
+        };
+        if let Err(err) = self.journal.append(&entry) {
+            error!("error writing journal: {}", err);
+        }
+        self.statistics.lock().unwrap().add(&finished);
+        {
+            let mut history = self.finished.lock().unwrap();
+            history.push_back(finished.clone());
+            self.trim(&mut history);
+        }
+        let _ = self.completions.send(finished);
+    }
+
+    fn trim(&self, history: &mut VecDeque<Finished>) {
+        while history.len() > self.size.load(Ordering::SeqCst) {
+            if let Some(finished) = history.pop_front() {
+                self.spool.remove(finished.task.id);
+            }
+        }
+    }
+
+    pub fn resize(&self, size: usize) {
+        self.size.store(size, Ordering::SeqCst);
+        self.trim(&mut self.finished.lock().unwrap());
+    }
+
+    pub fn cancel(&self, task: Task) {
+        self.record(Finished {
+            task,
+            state: TaskState::Cancelled,
+            status: None,
+            started: None,
+            ended: SystemTime::now(),
+        });
+    }
+
+    pub fn statistics(&self) -> Statistics {
+        *self.statistics.lock().unwrap()
+    }
+
+    pub fn find(&self, id: u64) -> Option<Finished> {
+        self.finished
+            .lock()
+            .unwrap()
+            .iter()
+            .find(|finished| finished.task.id == id)
+            .cloned()
+    }
+
+    pub fn collect<B>(&self) -> B
+    where
+        B: FromIterator<Finished>,
+    {
+        self.finished.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+pub struct Settings {
+    pub name: String,
+    pub max_parallel: usize,
+    pub output: Option<PathBuf>,
+    pub timeout: Option<Duration>,
+    pub dir: Option<PathBuf>,
+    pub retry: RetryPolicy,
+    pub kill: KillPolicy,
+    pub env: Environment,
+    pub history_size: usize,
+    pub paused: bool,
+}
+
+/// Settings that can be changed while the worker is running.
+pub struct Defaults {
+    pub output: Output,
+    pub timeout: Option<Duration>,
+    pub dir: Option<PathBuf>,
+    pub retry: RetryPolicy,
+    pub kill: KillPolicy,
+    pub env: Environment,
+}
+
 pub struct Worker {
+    name: String,
     queue: Arc<TaskQueue>,
-    output: Output,
+    running: RunningTasks,
+    history: Arc<History>,
+    scheduler: Scheduler,
+    paused: watch::Receiver<bool>,
+    paused_sender: Arc<watch::Sender<bool>>,
+    defaults: Arc<Mutex<Defaults>>,
     worker_shutdown: sync::Trigger,
     server_shutdown: sync::Trigger,
-    max_parallel: Arc<Semaphore>,
-    timeout: Option<Duration>,
-    dir: Option<PathBuf>,
+    max_parallel: Arc<sync::Limit>,
+    journal: Arc<Journal>,
+    spool: Arc<Spool>,
+    starts: Starts,
+    // Keeps the server running until all processes have exited
+    processes: sync::DropGuard,
 }
 
 impl Worker {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         queue: Arc<TaskQueue>,
-        output: Option<PathBuf>,
+        settings: Settings,
         server_shutdown: sync::Trigger,
-        max_parallel: usize,
-        timeout: Option<Duration>,
-        dir: Option<PathBuf>,
+        journal: Arc<Journal>,
+        completions: Completions,
+        starts: Starts,
+        spool: Arc<Spool>,
+        processes: sync::DropGuard,
     ) -> io::Result<Self> {
         let (worker_shutdown, _) = sync::condition();
-        let output = match output {
+        let output = match settings.output {
             Some(path) => Output::file(path)?,
             None => Output::new(io::stdout()),
         };
+        let scheduler = Scheduler::new(
+            queue.clone(),
+            worker_shutdown.clone(),
+            server_shutdown.clone(),
+        );
+        let (paused_sender, paused) = watch::channel(settings.paused);
         Ok(Worker {
+            name: settings.name.clone(),
             queue,
-            output,
+            paused,
+            paused_sender: Arc::new(paused_sender),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            history: Arc::new(History::new(
+                settings.name.clone(),
+                settings.history_size,
+                completions,
+                journal.clone(),
+                spool.clone(),
+            )),
+            scheduler,
+            defaults: Arc::new(Mutex::new(Defaults {
+                output,
+                timeout: settings.timeout,
+                dir: settings.dir,
+                retry: settings.retry,
+                kill: settings.kill,
+                env: settings.env,
+            })),
             worker_shutdown,
             server_shutdown,
-            max_parallel: Arc::new(Semaphore::new(max_parallel)),
-            timeout,
-            dir,
+            max_parallel: Arc::new(sync::Limit::new(settings.max_parallel)),
+            journal,
+            spool,
+            starts,
+            processes,
         })
     }
 
@@ -71,6 +356,30 @@ impl Worker {
         self.worker_shutdown.clone()
     }
 
+    pub fn running(&self) -> RunningTasks {
+        self.running.clone()
+    }
+
+    pub fn history(&self) -> Arc<History> {
+        self.history.clone()
+    }
+
+    pub fn scheduler(&self) -> Scheduler {
+        self.scheduler.clone()
+    }
+
+    pub fn defaults(&self) -> Arc<Mutex<Defaults>> {
+        self.defaults.clone()
+    }
+
+    pub fn max_parallel(&self) -> Arc<sync::Limit> {
+        self.max_parallel.clone()
+    }
+
+    pub fn pause_notifier(&self) -> Arc<watch::Sender<bool>> {
+        self.paused_sender.clone()
+    }
+
     pub async fn process(&self) {
         let mut server_shutdown = self.server_shutdown.waiter();
         let mut worker_shutdown = self.worker_shutdown.waiter();
@@ -82,28 +391,266 @@ impl Worker {
         debug!("queue worker shutting down");
     }
 
+    async fn next_task(&self) -> (Task, Arc<Capture>, sync::Waiter) {
+        let mut paused = self.paused.clone();
+        loop {
+            while *paused.borrow() {
+                if paused.changed().await.is_err() {
+                    std::future::pending::<()>().await;
+                }
+            }
+            tokio::select! {
+                ret = self.queue.pop_with(|task| self.start_task(task)) => return ret,
+                Ok(()) = paused.changed() => {},
+            }
+        }
+    }
+
+    /// Applies queue defaults to `task` and marks it running. This is called
+    /// with the queue locked so that the task is always either queued or
+    /// running.
+    fn start_task(&self, mut task: Task) -> (Task, Arc<Capture>, sync::Waiter) {
+        let defaults = self.defaults.lock().unwrap();
+        task.timeout = task.timeout.or(defaults.timeout);
+        let cwd = task.cwd.clone();
+        task.dir = task.dir.or_else(|| defaults.dir.clone()).or(cwd);
+        task.retry = task.retry.or(&defaults.retry);
+        task.kill = task.kill.or(&defaults.kill);
+        task.env = task.env.or(&defaults.env);
+        let (cancel, cancel_waiter) = sync::condition();
+        let capture = Arc::new(Capture::new(task.id, self.spool.clone()));
+        self.running.lock().unwrap().insert(
+            task.id,
+            Running {
+                task: task.clone(),
+                pid: None,
+                started: SystemTime::now(),
+                cancel,
+                capture: capture.clone(),
+            },
+        );
+        let _ = self.starts.send(task.id);
+        (task, capture, cancel_waiter)
+    }
+
+    fn open_stdin(&self, task: &Task) -> anyhow::Result<Stdio> {
+        let path = match &task.stdin {
+            Stdin::Null => return Ok(Stdio::null()),
+            Stdin::Spool => self.spool.stdin_path(task.id),
+            Stdin::File(path) => path.clone(),
+        };
+        let file =
+            File::open(&path).with_context(|| format!("opening '{}' failed", path.display()))?;
+        Ok(file.into())
+    }
+
+    fn create_spool(
+        &self,
+        capture: &Arc<Capture>,
+        task: &Task,
+        stream: Stream,
+    ) -> Option<CaptureWriter> {
+        // Output of all attempts is kept together
+        match capture.writer(stream, task.attempts == 0) {
+            Ok(writer) => Some(writer),
+            Err(err) => {
+                error!("error creating output file of task {}: {}", task.id, err);
+                None
+            }
+        }
+    }
+
     pub async fn process_inner(&self) {
         loop {
-            self.max_parallel.acquire().await.unwrap().forget();
-            let mut task = self.queue.pop().await;
-            task.timeout = task.timeout.or(self.timeout);
-            task.dir = task.dir.or_else(|| self.dir.clone());
+            self.max_parallel.acquire().await;
+            let (task, capture, cancel_waiter) = self.next_task().await;
+            let defaults = self.defaults.lock().unwrap();
             let done = self.max_parallel.clone();
+            let running = self.running.clone();
+            let history = self.history.clone();
+            let scheduler = self.scheduler.clone();
             let worker_shutdown = self.worker_shutdown.waiter();
             let server_shutdown = self.server_shutdown.waiter();
             // FIXME
-            let stdout = self.output.add_source().unwrap();
-            let stderr = self.output.add_source().unwrap();
+            let stdout = defaults
+                .output
+                .add_source(self.create_spool(&capture, &task, Stream::Stdout))
+                .unwrap();
+            let stderr = defaults
+                .output
+                .add_source(self.create_spool(&capture, &task, Stream::Stderr))
+                .unwrap();
+            drop(defaults);
+            let stdin = self.open_stdin(&task);
+            let journal = self.journal.clone();
+            let name = self.name.clone();
+            let processes = self.processes.clone();
 
             tokio::spawn(async move {
+                let id = task.id;
                 let command = task.to_string();
-                match Process::new(task, stdout, stderr, worker_shutdown, server_shutdown) {
-                    Ok(mut process) => process.wait().await,
-                    Err(err) => error!("error executing '{}': {}", command, err),
+                let process = stdin.and_then(|stdin| {
+                    Process::new(
+                        task,
+                        stdin,
+                        stdout,
+                        stderr,
+                        cancel_waiter,
+                        worker_shutdown,
+                        server_shutdown,
+                    )
+                });
+                let outcome = match process {
+                    Ok(mut process) => {
+                        if let Some(running) = running.lock().unwrap().get_mut(&id) {
+                            running.pid = process.id();
+                        }
+                        process.wait().await
+                    }
+                    Err(err) => {
+                        error!("error executing '{}': {}", command, err);
+                        Outcome::Failed
+                    }
+                };
+                // The task is recorded while holding the lock so that it is
+                // always either running or finished.
+                let mut running = running.lock().unwrap();
+                let task = running.remove(&id);
+                // Interrupted tasks are kept in the journal so that they get
+                // executed again when the server is restarted.
+                if let (
+                    Some(state),
+                    Some(Running {
+                        mut task, started, ..
+                    }),
+                ) = (outcome.state(), task)
+                {
+                    task.attempts += 1;
+                    if let Some(delay) = task.retry.retry_delay(task.attempts, &outcome) {
+                        info!(
+                            "task {} {}, retrying in {}",
+                            id,
+                            state,
+                            format_duration(delay)
+                        );
+                        let at = SystemTime::now() + delay;
+                        task.at = Some(at);
+                        // Replaces the earlier entry so that the attempts
+                        // made are not forgotten on restart
+                        let entry = Entry::Send {
+                            queue: name,
+                            task: task.clone(),
+                        };
+                        if let Err(err) = journal.append(&entry) {
+                            error!("error writing journal: {}", err);
+                        }
+                        scheduler.schedule(task, at);
+                    } else {
+                        info!("task {} {}", id, state);
+                        history.record(Finished {
+                            task,
+                            state,
+                            status: outcome.status(),
+                            started: Some(started),
+                            ended: SystemTime::now(),
+                        });
+                    }
                 }
-                done.add_permits(1);
+                drop(running);
+                drop(processes);
+                done.release();
                 debug!("process worker shutting down");
             });
         }
     }
 }
+
+/// Exit statuses are stored in their raw wait status form.
+mod exit_status {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S>(status: &Option<ExitStatus>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        status.map(|status| status.into_raw()).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<ExitStatus>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<i32>::deserialize(deserializer)?.map(ExitStatus::from_raw))
+    }
+}
+
+#[test]
+fn test_restore_statistics() {
+    let dir = std::env::temp_dir().join(format!("pqueue-history-{}", std::process::id()));
+    let (journal, _) = Journal::open(&dir).unwrap();
+    let spool = Spool::new(dir.join("spool")).unwrap();
+    let task = |id| Task {
+        id,
+        binary: "true".into(),
+        timeout: None,
+        dir: None,
+        cwd: None,
+        args: vec![],
+        priority: 0,
+        at: None,
+        after: vec![],
+        stdin: Default::default(),
+        retry: Default::default(),
+        kill: Default::default(),
+        env: Default::default(),
+        attempts: 0,
+    };
+    let finished = |id, state, runtime| {
+        let ended = SystemTime::UNIX_EPOCH + Duration::from_secs(100);
+        Entry::Finished {
+            queue: "a".into(),
+            finished: Finished {
+                task: task(id),
+                state,
+                status: None,
+                started: Some(ended - Duration::from_secs(runtime)),
+                ended,
+            },
+        }
+    };
+    let entries = vec![
+        Entry::CreateQueue(crate::request::CreateQueue {
+            name: "a".into(),
+            max_parallel: 1,
+            output: None,
+            timeout: None,
+            dir: None,
+            template: None,
+            retry: Default::default(),
+            kill: Default::default(),
+            env: Default::default(),
+            history_size: 100,
+        }),
+        finished(1, TaskState::Finished, 1),
+        finished(2, TaskState::Failed, 3),
+        finished(3, TaskState::Cancelled, 0),
+    ];
+    let mut queues = crate::journal::replay(entries);
+    let (completions, _) = broadcast::channel(1);
+    let history = History::new(
+        "a".into(),
+        100,
+        completions,
+        Arc::new(journal),
+        Arc::new(spool),
+    );
+    history.restore(queues.remove(0).history);
+    std::fs::remove_dir_all(&dir).unwrap();
+    let statistics = history.statistics();
+    assert_eq!(history.collect::<Vec<_>>().len(), 3);
+    assert_eq!((statistics.completed, statistics.failed), (1, 1));
+    assert_eq!(statistics.average_runtime(), Some(Duration::from_secs(2)));
+}